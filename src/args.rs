@@ -1,9 +1,12 @@
+use std::path::PathBuf;
+
 use clap::{Parser, ValueEnum};
 
 #[derive(Debug, Clone, ValueEnum)]
 pub enum Mode {
     Devices,
     Properties,
+    Calibrate,
     Run,
 }
 
@@ -15,11 +18,23 @@ pub struct Args {
     #[arg(short, long, default_value = "run")]
     pub mode: Mode,
 
-    /// Device (required in properties mode)
+    /// Device (required in properties and calibrate modes)
     #[arg(short, long)]
     pub device: Option<String>,
 
     /// Config file to run with
     #[arg(short, long, default_value = "device.conf")]
     pub config: String,
+
+    /// File axis calibrations are saved to in calibrate mode and read from when running
+    #[arg(long, default_value = "calibration.yaml")]
+    pub calibration: PathBuf,
+
+    /// Don't grab any input device, their events are seen by other programs as well
+    #[arg(long)]
+    pub no_grab: bool,
+
+    /// Profile to start with when the config file contains profiles
+    #[arg(short, long)]
+    pub profile: Option<String>,
 }
@@ -0,0 +1,144 @@
+use std::{collections::HashMap, error::Error, fs::File, io::ErrorKind, path::Path};
+
+use evdev::EventSummary;
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    error::FatalError,
+    ew_device::Device,
+    ew_types::{AbsInfo, AbsoluteAxisType},
+};
+
+/// Range of an axis observed while moving it, which may differ from what the driver reports
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct AxisCalibration {
+    pub min: i32,
+    pub max: i32,
+    /// Value at rest, the middle of the range for sticks or an end for throttles
+    pub centre: i32,
+}
+
+impl AxisCalibration {
+    fn new(value: i32) -> Self {
+        AxisCalibration {
+            min: value,
+            max: value,
+            centre: value,
+        }
+    }
+
+    fn update(&mut self, value: i32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// `info` with the calibrated range
+    pub fn apply_info(&self, info: AbsInfo) -> AbsInfo {
+        AbsInfo(evdev::AbsInfo::new(
+            self.apply(info.0.value()),
+            self.min,
+            self.max,
+            info.0.fuzz(),
+            info.0.flat(),
+            info.0.resolution(),
+        ))
+    }
+
+    /// Clamp a raw value to the calibrated range, moving the centre to the middle of it
+    pub fn apply(&self, value: i32) -> i32 {
+        let value = value.clamp(self.min, self.max);
+
+        // Throttles rest at one end so there is no centre to move
+        if self.centre <= self.min || self.centre >= self.max {
+            return value;
+        }
+
+        let (min, max, centre) = (self.min as f64, self.max as f64, self.centre as f64);
+        let middle = (min + max) / 2.0;
+        let scaled = match value < self.centre {
+            true => middle - (centre - value as f64) / (centre - min) * (middle - min),
+            false => middle + (value as f64 - centre) / (max - centre) * (max - middle),
+        };
+
+        scaled.round() as i32
+    }
+}
+
+/// Calibrated axes of a device
+pub type DeviceCalibration = HashMap<AbsoluteAxisType, AxisCalibration>;
+
+/// Calibrated axes of each device keyed by `device_key`
+pub type Calibrations = HashMap<String, DeviceCalibration>;
+
+/// Name and physical path of a device so identical devices are calibrated separately
+pub fn device_key(device: &Device) -> Option<String> {
+    let name = device.name()?;
+    Some(match device.physical_path() {
+        Some(phys) => format!("{} ({})", name, phys),
+        None => name.to_string(),
+    })
+}
+
+pub fn load(path: &Path) -> Result<Calibrations, FatalError> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_yaml::from_reader(file)?),
+        // Nothing has been calibrated yet
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Calibrations::new()),
+        Err(e) => Err(e)?,
+    }
+}
+
+fn save(path: &Path, calibrations: &Calibrations) -> Result<(), FatalError> {
+    let file = File::create(path)?;
+    serde_yaml::to_writer(file, calibrations)?;
+    Ok(())
+}
+
+/// Record the range of every axis of a device until interrupted then save it,
+/// replacing any earlier calibration of the same device
+pub async fn calibrate(device_path: String, file: &Path) -> Result<(), Box<dyn Error>> {
+    let device = Device::open(device_path)?;
+    let name = device_key(&device).ok_or("Only named devices can be calibrated")?;
+
+    // Axes are assumed to be at rest when starting
+    let mut calibration: DeviceCalibration = device
+        .get_abs_state()?
+        .into_iter()
+        .map(|(axis, info)| (axis, AxisCalibration::new(info.0.value())))
+        .collect();
+
+    let mut stream = device.into_event_stream()?;
+
+    println!("Calibrating {:?}.", name);
+    println!("Move every axis through its full range then press Ctrl+C to save.");
+
+    let mut interrupt_signal = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            event = stream.next_event() => {
+                if let EventSummary::AbsoluteAxis(_, code, value) = event?.kind() {
+                    if let Some(c) = calibration.get_mut(&AbsoluteAxisType(code)) {
+                        c.update(value);
+                    }
+                }
+            }
+            _ = interrupt_signal.recv() => break,
+        }
+    }
+
+    // Axes that never moved would be stuck at a single value
+    calibration.retain(|_, c| c.max > c.min);
+
+    for (axis, c) in calibration.iter() {
+        println!("\t{:?}: {:?}", axis, c);
+    }
+
+    let mut calibrations = load(file)?;
+    calibrations.insert(name, calibration);
+    save(file, &calibrations)?;
+
+    info!("Saved calibration to {:?}", file);
+    Ok(())
+}
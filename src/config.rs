@@ -1,30 +1,403 @@
 use crate::{
     error::{FatalError, NonFatalError},
-    ew_types::{self, AbsoluteAxisType, InputEvent, KeyCode, Synchronization},
+    ew_types::{
+        self, AbsInfo, AbsoluteAxisType, InputEvent, KeyCode, RelativeAxisType, Synchronization,
+    },
+    expression::Expression,
 };
 use evdev::EventSummary;
 use serde::Deserialize;
-use std::{collections::HashMap, fs::File, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Name of the profile used when the config file has a top level `devices:` list
+pub const DEFAULT_PROFILE: &str = "default";
 
 #[derive(Debug, Deserialize)]
 struct Config {
+    #[serde(default)]
+    devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    combine: Vec<CombinedAxisConfig>,
+    #[serde(default)]
+    expressions: Vec<ExpressionAxisConfig>,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    shared_axes: SharedAxisPolicy,
+    #[serde(default)]
+    profiles: BTreeMap<String, ProfileConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileConfig {
+    #[serde(default)]
+    switch: Option<SwitchConfig>,
     devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    combine: Vec<CombinedAxisConfig>,
+    #[serde(default)]
+    expressions: Vec<ExpressionAxisConfig>,
+    /// Overrides the top level policy
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    shared_axes: Option<SharedAxisPolicy>,
 }
 
+/// Button combination on a single device that activates a profile
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
+pub enum SwitchConfig {
+    ByPath { path: PathBuf, keys: Vec<KeyCode> },
+    ByName { name: String, keys: Vec<KeyCode> },
+}
+
+/// A device by path or name
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceRef {
+    ByPath { path: PathBuf },
+    ByName { name: String },
+}
+
+impl DeviceRef {
+    pub fn id(&self) -> ControllerId {
+        match self {
+            DeviceRef::ByPath { path } => ControllerId::Path(path.clone()),
+            DeviceRef::ByName { name } => ControllerId::Name(name.clone()),
+        }
+    }
+}
+
+/// What to do when more than one mapping writes the same absolute axis
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedAxisPolicy {
+    /// Refuse to load the config
+    #[default]
+    Error,
+    /// The last value written
+    LastWriter,
+    /// The value furthest from the centre
+    LargestDeflection,
+    /// The first device in the list that has the axis moved from the centre
+    Priority(Vec<DeviceRef>),
+}
+
+impl SharedAxisPolicy {
+    /// Position of a device in the priority list, lower is more important
+    pub fn rank(&self, id: &ControllerId) -> usize {
+        match self {
+            SharedAxisPolicy::Priority(devices) => devices
+                .iter()
+                .position(|d| d.id() == *id)
+                .unwrap_or(devices.len()),
+            _ => 0,
+        }
+    }
+}
+
+/// An axis on a specific device
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceAxis {
+    ByPath {
+        path: PathBuf,
+        axis: AbsoluteAxisType,
+    },
+    ByName {
+        name: String,
+        axis: AbsoluteAxisType,
+    },
+}
+
+impl DeviceAxis {
+    pub fn id(&self) -> ControllerId {
+        match self {
+            DeviceAxis::ByPath { path, axis: _ } => ControllerId::Path(path.clone()),
+            DeviceAxis::ByName { name, axis: _ } => ControllerId::Name(name.clone()),
+        }
+    }
+
+    pub fn axis(&self) -> AbsoluteAxisType {
+        match self {
+            DeviceAxis::ByPath { path: _, axis } => *axis,
+            DeviceAxis::ByName { name: _, axis } => *axis,
+        }
+    }
+
+    pub fn input(&self) -> UniqueControllerEvent {
+        UniqueControllerEvent::new(self.id(), ControllerInputEvent::AbsAxis(self.axis()))
+    }
+}
+
+/// A button on a specific device
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceKey {
+    ByPath { path: PathBuf, key: KeyCode },
+    ByName { name: String, key: KeyCode },
+}
+
+impl DeviceKey {
+    pub fn input(&self) -> UniqueControllerEvent {
+        let (id, key) = match self {
+            DeviceKey::ByPath { path, key } => (ControllerId::Path(path.clone()), *key),
+            DeviceKey::ByName { name, key } => (ControllerId::Name(name.clone()), *key),
+        };
+
+        UniqueControllerEvent::new(id, ControllerInputEvent::Key(key))
+    }
+}
+
+/// When a mapping is active, inputs can be on any device and values are as reported by the device
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Condition {
+    Held {
+        held: DeviceKey,
+    },
+    Axis {
+        axis: DeviceAxis,
+        #[serde(default)]
+        above: Option<i32>,
+        #[serde(default)]
+        below: Option<i32>,
+    },
+    Layer {
+        layer: String,
+    },
+    Not {
+        not: Box<Condition>,
+    },
+    All {
+        all: Vec<Condition>,
+    },
+}
+
+impl Condition {
+    /// Every input the condition depends on
+    pub fn inputs(&self) -> Vec<UniqueControllerEvent> {
+        match self {
+            Condition::Held { held } => vec![held.input()],
+            Condition::Axis { axis, .. } => vec![axis.input()],
+            Condition::Layer { layer: _ } => vec![],
+            Condition::Not { not } => not.inputs(),
+            Condition::All { all } => all.iter().flat_map(|c| c.inputs()).collect(),
+        }
+    }
+}
+
+/// A mapping and when it is active
+#[derive(Clone, Debug, Deserialize)]
+pub struct MappingConfig {
+    #[serde(flatten)]
+    pub mapping: EventMapping,
+    /// Only active while this holds, always active if not set
+    #[serde(default)]
+    pub when: Option<Condition>,
+}
+
+impl From<EventMapping> for MappingConfig {
+    fn from(mapping: EventMapping) -> Self {
+        MappingConfig {
+            mapping,
+            when: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CombineOp {
+    Sum,
+    /// First input minus the second
+    Difference,
+    Max,
+    Average,
+}
+
+/// Two axes, possibly on different devices, combined into one output axis
+#[derive(Clone, Debug, Deserialize)]
+pub struct CombinedAxisConfig {
+    pub inputs: [DeviceAxis; 2],
+    pub op: CombineOp,
+    pub output: AbsoluteAxisType,
+    /// Ends of the output axis, otherwise the range the inputs reach together
+    #[serde(default)]
+    pub min: Option<i32>,
+    #[serde(default)]
+    pub max: Option<i32>,
+}
+
+/// How an axis value is scaled for an expression
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueRange {
+    /// -1.0 to 1.0
+    #[default]
+    Signed,
+    /// 0.0 to 1.0
+    Unsigned,
+}
+
+impl ValueRange {
+    /// Scale a value from -1.0..1.0 to this range
+    pub fn scale(self, value: f64) -> f64 {
+        match self {
+            ValueRange::Signed => value,
+            ValueRange::Unsigned => (value + 1.0) / 2.0,
+        }
+    }
+
+    /// Scale a value from this range to -1.0..1.0
+    pub fn normalise(self, value: f64) -> f64 {
+        match self {
+            ValueRange::Signed => value,
+            ValueRange::Unsigned => value * 2.0 - 1.0,
+        }
+    }
+}
+
+/// An axis used by an expression
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExpressionInput {
+    #[serde(flatten)]
+    pub axis: DeviceAxis,
+    #[serde(default)]
+    pub range: ValueRange,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExpressionAxisFields {
+    inputs: BTreeMap<String, ExpressionInput>,
+    expression: String,
+    output: AbsoluteAxisType,
+    #[serde(default)]
+    range: ValueRange,
+    #[serde(default)]
+    min: Option<i32>,
+    #[serde(default)]
+    max: Option<i32>,
+}
+
+/// Axes, possibly on different devices, combined into one output axis by an expression
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ExpressionAxisFields")]
+pub struct ExpressionAxisConfig {
+    /// In the order their values are given to the expression
+    pub inputs: Vec<ExpressionInput>,
+    pub expression: Expression,
+    pub output: AbsoluteAxisType,
+    /// Range of the expression's result
+    pub range: ValueRange,
+    /// Range of the output axis, otherwise the range of the first input
+    pub output_range: Option<OutputRange>,
+}
+
+impl TryFrom<ExpressionAxisFields> for ExpressionAxisConfig {
+    type Error = String;
+
+    fn try_from(fields: ExpressionAxisFields) -> Result<Self, Self::Error> {
+        if fields.inputs.is_empty() {
+            Err("An expression needs at least one input")?;
+        }
+
+        let names: Vec<String> = fields.inputs.keys().cloned().collect();
+        let expression = Expression::parse(&fields.expression, &names)?;
+
+        let output_range = match (fields.min, fields.max) {
+            (Some(min), Some(max)) => Some(OutputRange { min, max }),
+            (None, None) => None,
+            _ => Err("An expression's output needs both min and max or neither")?,
+        };
 
+        Ok(ExpressionAxisConfig {
+            inputs: fields.inputs.into_values().collect(),
+            expression,
+            output: fields.output,
+            range: fields.range,
+            output_range,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
 pub enum DeviceConfig {
     ByPath {
         path: PathBuf,
-        mappings: Vec<EventMapping>,
+        #[serde(default)]
+        unmapped: UnmappedPolicy,
+        /// Take exclusive use of the device so other programs don't see its events
+        #[serde(default = "default_grab")]
+        grab: bool,
+        mappings: Vec<MappingConfig>,
     },
     ByName {
         name: String,
-        mappings: Vec<EventMapping>,
+        #[serde(default)]
+        unmapped: UnmappedPolicy,
+        /// Take exclusive use of the device so other programs don't see its events
+        #[serde(default = "default_grab")]
+        grab: bool,
+        mappings: Vec<MappingConfig>,
     },
 }
 
+fn default_grab() -> bool {
+    true
+}
+
+/// The parts of a `DeviceConfig` that don't depend on how the device is found
+struct DeviceSettings {
+    id: ControllerId,
+    unmapped: UnmappedPolicy,
+    grab: bool,
+    mappings: Vec<MappingConfig>,
+}
+
+impl DeviceConfig {
+    fn split(self) -> DeviceSettings {
+        match self {
+            DeviceConfig::ByPath {
+                path,
+                unmapped,
+                grab,
+                mappings,
+            } => DeviceSettings {
+                id: ControllerId::Path(path),
+                unmapped,
+                grab,
+                mappings,
+            },
+            DeviceConfig::ByName {
+                name,
+                unmapped,
+                grab,
+                mappings,
+            } => DeviceSettings {
+                id: ControllerId::Name(name),
+                unmapped,
+                grab,
+                mappings,
+            },
+        }
+    }
+}
+
+/// What happens to events from a device that aren't used by any mapping
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmappedPolicy {
+    Drop,
+    /// Written unchanged to the virtual device
+    Passthrough,
+    #[default]
+    Warn,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(untagged)]
 pub enum EventMapping {
@@ -36,13 +409,541 @@ pub enum EventMapping {
         input: AbsoluteAxisType,
         output: AbsAxisEvent,
     },
+    AxisPair {
+        input: AxisPair,
+        output: AxisPairEvent,
+    },
+    RelAxisEvent {
+        input: RelativeAxisType,
+        output: EncoderMapping,
+    },
+    ButtonAxis {
+        input: RampButtons,
+        output: RampAxis,
+    },
+    ButtonTrim {
+        input: TrimButtons,
+        output: TrimConfig,
+    },
+    KeyCombo {
+        input: KeyCode,
+        output: KeyCombo,
+    },
+    Layer {
+        input: KeyCode,
+        output: LayerConfig,
+    },
+    Script {
+        #[serde(deserialize_with = "non_empty")]
+        input: Vec<InputCode>,
+        output: ScriptConfig,
+    },
+    /// Set from the `combine` list rather than a device's mappings
+    #[serde(skip)]
+    CombinedAxis(CombinedAxisConfig),
+    /// Set from the `expressions` list rather than a device's mappings
+    #[serde(skip)]
+    ExpressionAxis(ExpressionAxisConfig),
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+impl EventMapping {
+    /// Every input that feeds this mapping in the order the mapping expects them
+    pub fn inputs(&self, id: &ControllerId) -> Vec<UniqueControllerEvent> {
+        let events = match self {
+            EventMapping::KeyEvent { input, output: _ } => vec![ControllerInputEvent::Key(*input)],
+            EventMapping::KeyCombo { input, output: _ } => vec![ControllerInputEvent::Key(*input)],
+            EventMapping::Layer { input, output: _ } => vec![ControllerInputEvent::Key(*input)],
+            EventMapping::AbsAxisEvent { input, output: _ } => {
+                vec![ControllerInputEvent::AbsAxis(*input)]
+            }
+            EventMapping::AxisPair { input, output: _ } => vec![
+                ControllerInputEvent::AbsAxis(input.x),
+                ControllerInputEvent::AbsAxis(input.y),
+            ],
+            EventMapping::RelAxisEvent { input, output: _ } => {
+                vec![ControllerInputEvent::RelAxis(*input)]
+            }
+            EventMapping::ButtonAxis { input, output: _ } => [input.increase, input.decrease]
+                .into_iter()
+                .chain(input.centre)
+                .map(ControllerInputEvent::Key)
+                .collect(),
+            EventMapping::ButtonTrim { input, output: _ } => [input.increase, input.decrease]
+                .into_iter()
+                .chain(input.reset)
+                .map(ControllerInputEvent::Key)
+                .collect(),
+            EventMapping::Script { input, output: _ } => input
+                .iter()
+                .map(|&i| ControllerInputEvent::from(i))
+                .collect(),
+            // Inputs can be on other devices
+            EventMapping::CombinedAxis(c) => return c.inputs.iter().map(|i| i.input()).collect(),
+            EventMapping::ExpressionAxis(e) => {
+                return e.inputs.iter().map(|i| i.axis.input()).collect()
+            }
+        };
+
+        events
+            .into_iter()
+            .map(|e| UniqueControllerEvent::new(id.clone(), e))
+            .collect()
+    }
+}
+
+/// Buttons that move an axis while held
+#[derive(Clone, Debug, Deserialize)]
+pub struct RampButtons {
+    pub increase: KeyCode,
+    pub decrease: KeyCode,
+    /// Moves the axis back to the centre when pressed
+    #[serde(default)]
+    pub centre: Option<KeyCode>,
+}
+
+/// Output axis moved by `RampButtons`
+#[derive(Clone, Debug, Deserialize)]
+pub struct RampAxis {
+    pub axis: AbsoluteAxisType,
+    /// Fraction of the full range moved per second while a button is held
+    #[serde(default = "default_ramp_rate")]
+    pub rate: f64,
+    #[serde(default = "default_axis_min")]
+    pub min: i32,
+    #[serde(default = "default_axis_max")]
+    pub max: i32,
+    /// How often the axis is updated while a button is held in milliseconds
+    #[serde(default = "default_ramp_tick_ms")]
+    pub tick_ms: u64,
+}
+
+impl RampAxis {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms.max(1))
+    }
+}
+
+fn default_ramp_rate() -> f64 {
+    1.0
+}
+
+fn default_axis_min() -> i32 {
+    -32768
+}
+
+fn default_axis_max() -> i32 {
+    32767
+}
+
+fn default_ramp_tick_ms() -> u64 {
+    10
+}
+
+/// Keys pressed together on the virtual keyboard, modifiers first
+#[derive(Clone, Debug, Deserialize)]
+pub struct KeyCombo {
+    pub combo: Vec<KeyCode>,
+}
+
+/// A named layer, active while the button is held or toggled by each press
+#[derive(Clone, Debug, Deserialize)]
+pub struct LayerConfig {
+    pub layer: String,
+    #[serde(default)]
+    pub toggle: bool,
+}
+
+/// Any input of a device
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InputCode {
+    Key(KeyCode),
+    AbsAxis(AbsoluteAxisType),
+    RelAxis(RelativeAxisType),
+}
+
+impl From<InputCode> for ControllerInputEvent {
+    fn from(input: InputCode) -> Self {
+        match input {
+            InputCode::Key(k) => ControllerInputEvent::Key(k),
+            InputCode::AbsAxis(a) => ControllerInputEvent::AbsAxis(a),
+            InputCode::RelAxis(r) => ControllerInputEvent::RelAxis(r),
+        }
+    }
+}
+
+fn non_empty<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let items = Vec::<T>::deserialize(deserializer)?;
+    match items.is_empty() {
+        true => Err(serde::de::Error::invalid_length(0, &"at least one")),
+        false => Ok(items),
+    }
+}
+
+/// An absolute axis written by a script
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScriptAxis {
+    pub axis: AbsoluteAxisType,
+    pub min: i32,
+    pub max: i32,
+}
+
+/// A script called with every change of its inputs, along with the buttons and axes it may write
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScriptConfig {
+    pub script: PathBuf,
+    #[serde(default)]
+    pub keys: Vec<KeyCode>,
+    #[serde(default)]
+    pub axes: Vec<ScriptAxis>,
+}
+
+/// Buttons that move the trim of an axis one step per press
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrimButtons {
+    pub increase: KeyCode,
+    pub decrease: KeyCode,
+    /// Sets the trim back to zero when pressed
+    #[serde(default)]
+    pub reset: Option<KeyCode>,
+}
+
+/// Offset added to an output axis written by other mappings
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrimConfig {
+    pub trim: AbsoluteAxisType,
+    /// Fraction of the full range moved per press
+    #[serde(default = "default_trim_step")]
+    pub step: f64,
+    /// Largest offset either way as a fraction of the full range
+    #[serde(default = "default_trim_limit")]
+    pub limit: f64,
+    /// File the trim is saved to so it is kept between runs
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
+fn default_trim_step() -> f64 {
+    0.01
+}
+
+fn default_trim_limit() -> f64 {
+    0.25
+}
+
+/// Two axes of a device treated as a single stick
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub struct AxisPair {
+    pub x: AbsoluteAxisType,
+    pub y: AbsoluteAxisType,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum AxisPairEvent {
+    Dpad {
+        /// Fraction of full deflection ignored around the centre
+        #[serde(default)]
+        deadzone: f64,
+        dpad: DpadKeys,
+        /// Press two keys on the diagonals instead of only the nearest one
+        #[serde(default = "default_eight_way")]
+        eight_way: bool,
+    },
+    Sectors {
+        /// Fraction of full deflection ignored around the centre
+        #[serde(default)]
+        deadzone: f64,
+        /// One key per equally sized sector, clockwise starting from up
+        sectors: Vec<KeyCode>,
+    },
+    StickAxis {
+        axes: AxisPair,
+        /// Ends of both output axes, otherwise those of the inputs
+        #[serde(default)]
+        min: Option<i32>,
+        #[serde(default)]
+        max: Option<i32>,
+        #[serde(flatten)]
+        shape: StickShape,
+    },
+    Mouse {
+        mouse: MouseConfig,
+    },
+}
+
+/// Moves the pointer while a stick is deflected
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MouseConfig {
+    /// Pixels per second at full deflection
+    #[serde(default = "default_mouse_speed")]
+    pub speed: f64,
+    /// Exponent of the response curve, 1.0 is linear and higher is slower near the centre
+    #[serde(default = "default_mouse_acceleration")]
+    pub acceleration: f64,
+    /// Fraction of full deflection ignored around the centre
+    #[serde(default)]
+    pub deadzone: f64,
+    /// How often to move the pointer in milliseconds
+    #[serde(default = "default_mouse_tick_ms")]
+    pub tick_ms: u64,
+}
+
+impl MouseConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms.max(1))
+    }
+}
+
+fn default_mouse_speed() -> f64 {
+    1000.0
+}
+
+fn default_mouse_acceleration() -> f64 {
+    1.0
+}
+
+fn default_mouse_tick_ms() -> u64 {
+    10
+}
+
+fn default_eight_way() -> bool {
+    true
+}
+
+/// Radial response of a stick, all values are fractions of full deflection
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StickShape {
+    /// Ignored around the centre
+    #[serde(default)]
+    pub deadzone: f64,
+    /// Smallest output once outside the deadzone
+    #[serde(default)]
+    pub anti_deadzone: f64,
+    /// Treated as full deflection
+    #[serde(default = "default_saturation")]
+    pub saturation: f64,
+    /// Stretch a circular range of movement out to the corners of the square
+    #[serde(default)]
+    pub square: bool,
+}
+
+fn default_saturation() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DpadKeys {
+    pub up: KeyCode,
+    pub right: KeyCode,
+    pub down: KeyCode,
+    pub left: KeyCode,
+}
+
+impl DpadKeys {
+    /// Keys pressed in each sector, clockwise starting from up
+    pub fn sectors(&self, eight_way: bool) -> Vec<Vec<KeyCode>> {
+        if eight_way {
+            vec![
+                vec![self.up],
+                vec![self.up, self.right],
+                vec![self.right],
+                vec![self.right, self.down],
+                vec![self.down],
+                vec![self.down, self.left],
+                vec![self.left],
+                vec![self.left, self.up],
+            ]
+        } else {
+            vec![
+                vec![self.up],
+                vec![self.right],
+                vec![self.down],
+                vec![self.left],
+            ]
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(untagged)]
 pub enum AbsAxisEvent {
     AbsAxis(AbsoluteAxisType),
+    /// The same axis rescaled to a different range
+    Scaled(ScaledAxis),
     FilteredKeys(Vec<FilteredKeyMapping>),
+    /// Each half of a centred axis to its own full range output axis
+    Split {
+        negative: AbsoluteAxisType,
+        positive: AbsoluteAxisType,
+        /// Ends of both output axes, otherwise those of the input
+        #[serde(default)]
+        min: Option<i32>,
+        #[serde(default)]
+        max: Option<i32>,
+    },
+    /// Taps for a wrapping absolute axis
+    Encoder(EncoderMapping),
+    /// The same axis snapping to positions near detents
+    Detents {
+        axis: AbsoluteAxisType,
+        detents: Vec<Detent>,
+        /// Ends of the output axis, otherwise those of the input
+        #[serde(default)]
+        min: Option<i32>,
+        #[serde(default)]
+        max: Option<i32>,
+    },
+    /// The same axis with noise filtered out
+    Smoothed(SmoothedAxis),
+}
+
+/// Range of an output axis, independent of the range of its input
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct OutputRange {
+    pub min: i32,
+    pub max: i32,
+}
+
+impl OutputRange {
+    /// An axis with this range, resting at the centre
+    pub fn axis_info(&self) -> AbsInfo {
+        AbsInfo(evdev::AbsInfo::new(0, self.min, self.max, 0, 0, 0)).clone_set_normalised(0.0)
+    }
+
+    /// The output axis for `input` with either end replaced, at the same position in the range
+    pub fn output_info(min: Option<i32>, max: Option<i32>, input: AbsInfo) -> AbsInfo {
+        if min.is_none() && max.is_none() {
+            return input;
+        }
+
+        let range = OutputRange {
+            min: min.unwrap_or(input.0.minimum()),
+            max: max.unwrap_or(input.0.maximum()),
+        };
+        range.axis_info().clone_set_normalised(input.normalised())
+    }
+}
+
+/// An axis written with its own range
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScaledAxis {
+    pub axis: AbsoluteAxisType,
+    pub min: i32,
+    pub max: i32,
+}
+
+impl ScaledAxis {
+    pub fn range(&self) -> OutputRange {
+        OutputRange {
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+/// An axis passed through a noise filter
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SmoothedAxis {
+    pub axis: AbsoluteAxisType,
+    #[serde(default)]
+    pub filter: Option<AxisFilter>,
+    /// Changes in the input smaller than this are ignored
+    #[serde(default)]
+    pub fuzz: i32,
+    /// How often the filter is updated after the input stops in milliseconds
+    #[serde(default = "default_filter_tick_ms")]
+    pub tick_ms: u64,
+    /// Ends of the output axis, otherwise those of the input
+    #[serde(default)]
+    pub min: Option<i32>,
+    #[serde(default)]
+    pub max: Option<i32>,
+}
+
+impl SmoothedAxis {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms.max(1))
+    }
+}
+
+fn default_filter_tick_ms() -> u64 {
+    10
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AxisFilter {
+    /// Mean of the last `samples` values
+    MovingAverage { samples: usize },
+    /// Moves `alpha` of the way towards each new value
+    Exponential { alpha: f64 },
+    /// Smooths heavily while the axis moves slowly and lightly while it moves quickly
+    OneEuro {
+        /// Cutoff frequency in Hz while the axis is still
+        #[serde(default = "default_one_euro_cutoff")]
+        min_cutoff: f64,
+        /// How quickly the cutoff rises with speed
+        #[serde(default)]
+        beta: f64,
+        /// Cutoff frequency in Hz used to smooth the speed
+        #[serde(default = "default_one_euro_cutoff")]
+        d_cutoff: f64,
+    },
+}
+
+fn default_one_euro_cutoff() -> f64 {
+    1.0
+}
+
+/// A position of an axis the output snaps to when the input is close to it
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub struct Detent {
+    pub position: i32,
+    /// How far either side of `position` the input snaps to it
+    pub window: i32,
+    /// Pressed while the input is in the detent
+    #[serde(default)]
+    pub key: Option<KeyCode>,
+}
+
+impl Detent {
+    pub fn contains(&self, value: i32) -> bool {
+        (value - self.position).abs() <= self.window
+    }
+}
+
+/// Turns increments of an encoder wheel into taps of one key per direction
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub struct EncoderMapping {
+    pub increase: KeyCode,
+    pub decrease: KeyCode,
+    /// Change in value for each tap
+    #[serde(default = "default_encoder_step")]
+    pub step: i32,
+    /// How long each tap and the gap after it last in milliseconds
+    #[serde(default = "default_encoder_tap_ms")]
+    pub tap_ms: u64,
+}
+
+impl EncoderMapping {
+    pub fn tap(&self) -> Duration {
+        Duration::from_millis(self.tap_ms)
+    }
+}
+
+fn default_encoder_step() -> i32 {
+    1
+}
+
+fn default_encoder_tap_ms() -> u64 {
+    50
 }
 
 impl From<AbsoluteAxisType> for AbsAxisEvent {
@@ -63,6 +964,9 @@ impl TryFrom<InputEvent> for ControllerInputEvent {
             EventSummary::AbsoluteAxis(_e, c, _vv) => {
                 Ok(ControllerInputEvent::AbsAxis(ew_types::AbsoluteAxisType(c)))
             }
+            EventSummary::RelativeAxis(_e, c, _v) => {
+                Ok(ControllerInputEvent::RelAxis(ew_types::RelativeAxisType(c)))
+            }
             _ => Err(NonFatalError::from(
                 "Conversion from {:?} to ControllerEvent not implemented",
             )),
@@ -75,6 +979,18 @@ pub struct FilteredKeyMapping {
     pub min: i32,
     pub max: i32,
     pub key: KeyCode,
+    /// How far the axis must move back outside min / max before the key is released
+    #[serde(default)]
+    pub hysteresis: i32,
+    /// How long in milliseconds the axis must stay inside / outside the zone before the key changes
+    #[serde(default)]
+    pub hold_ms: u64,
+}
+
+impl FilteredKeyMapping {
+    pub fn hold(&self) -> Duration {
+        Duration::from_millis(self.hold_ms)
+    }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -86,6 +1002,7 @@ pub enum ControllerId {
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum ControllerInputEvent {
     AbsAxis(AbsoluteAxisType),
+    RelAxis(RelativeAxisType),
     Key(KeyCode),
     Synchronization(Synchronization),
 }
@@ -105,7 +1022,20 @@ impl From<EventMapping> for ControllerInputEvent {
     fn from(mapping: EventMapping) -> Self {
         match mapping {
             EventMapping::KeyEvent { input, output: _ } => ControllerInputEvent::Key(input),
+            EventMapping::KeyCombo { input, output: _ } => ControllerInputEvent::Key(input),
+            EventMapping::Layer { input, output: _ } => ControllerInputEvent::Key(input),
             EventMapping::AbsAxisEvent { input, output: _ } => ControllerInputEvent::AbsAxis(input),
+            EventMapping::AxisPair { input, output: _ } => ControllerInputEvent::AbsAxis(input.x),
+            EventMapping::RelAxisEvent { input, output: _ } => ControllerInputEvent::RelAxis(input),
+            EventMapping::ButtonAxis { input, output: _ } => {
+                ControllerInputEvent::Key(input.increase)
+            }
+            EventMapping::ButtonTrim { input, output: _ } => {
+                ControllerInputEvent::Key(input.increase)
+            }
+            EventMapping::Script { input, output: _ } => input[0].into(),
+            EventMapping::CombinedAxis(c) => c.inputs[0].input().event,
+            EventMapping::ExpressionAxis(e) => e.inputs[0].axis.input().event,
         }
     }
 }
@@ -125,30 +1055,175 @@ impl UniqueControllerEvent {
     }
 }
 
-pub type ConfigMap = HashMap<UniqueControllerEvent, EventMapping>;
-pub fn read(path: &String) -> Result<ConfigMap, FatalError> {
-    let file = File::open(path)?;
+/// Every mapping with the input it is declared for, an input can appear more than once
+pub type ConfigMap = Vec<(UniqueControllerEvent, MappingConfig)>;
 
-    let config: Config = serde_yaml::from_reader(file)?;
+#[derive(Clone, Debug)]
+pub struct ProfileSwitch {
+    pub id: ControllerId,
+    pub keys: HashSet<KeyCode>,
+}
 
-    let config_map: HashMap<_, _> = config
-        .devices
-        .into_iter()
-        .flat_map(mappings_to_map)
-        .collect();
+impl From<SwitchConfig> for ProfileSwitch {
+    fn from(config: SwitchConfig) -> Self {
+        let (id, keys) = match config {
+            SwitchConfig::ByPath { path, keys } => (ControllerId::Path(path), keys),
+            SwitchConfig::ByName { name, keys } => (ControllerId::Name(name), keys),
+        };
+
+        ProfileSwitch {
+            id,
+            keys: keys.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub mappings: ConfigMap,
+    pub switch: Option<ProfileSwitch>,
+    /// Devices that don't warn about unmapped events
+    pub unmapped: HashMap<ControllerId, UnmappedPolicy>,
+    /// Devices that are not grabbed
+    pub ungrabbed: HashSet<ControllerId>,
+    pub shared_axes: SharedAxisPolicy,
+}
 
-    println!("{:?}", config_map);
-    Ok(config_map)
+impl Profile {
+    fn new(
+        devices: Vec<DeviceConfig>,
+        combine: Vec<CombinedAxisConfig>,
+        expressions: Vec<ExpressionAxisConfig>,
+        switch: Option<SwitchConfig>,
+        shared_axes: SharedAxisPolicy,
+    ) -> Self {
+        let combined = combine.into_iter().map(|c| {
+            let ue = c.inputs[0].input();
+            (ue, EventMapping::CombinedAxis(c).into())
+        });
+        let expressions = expressions.into_iter().map(|e| {
+            let ue = e.inputs[0].axis.input();
+            (ue, EventMapping::ExpressionAxis(e).into())
+        });
+
+        let devices: Vec<_> = devices.into_iter().map(DeviceConfig::split).collect();
+
+        let unmapped = devices
+            .iter()
+            .filter(|d| d.unmapped != UnmappedPolicy::Warn)
+            .map(|d| (d.id.clone(), d.unmapped))
+            .collect();
+
+        let ungrabbed = devices
+            .iter()
+            .filter(|d| !d.grab)
+            .map(|d| d.id.clone())
+            .collect();
+
+        let mappings = devices
+            .into_iter()
+            .flat_map(|d| mappings_to_map(d.id, d.mappings))
+            .chain(combined)
+            .chain(expressions)
+            .collect();
+        Profile {
+            mappings,
+            switch: switch.map(ProfileSwitch::from),
+            unmapped,
+            ungrabbed,
+            shared_axes,
+        }
+    }
+
+    pub fn unmapped_policy(&self, id: &ControllerId) -> UnmappedPolicy {
+        self.unmapped.get(id).copied().unwrap_or_default()
+    }
 }
 
-fn mappings_to_map(config: DeviceConfig) -> HashMap<UniqueControllerEvent, EventMapping> {
-    let (id, mappings) = match config {
-        DeviceConfig::ByPath { path, mappings } => (ControllerId::Path(path), mappings),
-        DeviceConfig::ByName { name, mappings } => (ControllerId::Name(name), mappings),
+pub type Profiles = BTreeMap<String, Profile>;
+
+/// All the controllers used by any profile including those only used to switch profiles
+/// or with an unmapped policy, and the inputs used from each of them
+pub fn controller_ids(profiles: &Profiles) -> HashMap<ControllerId, HashSet<ControllerInputEvent>> {
+    let mut ids: HashMap<ControllerId, HashSet<ControllerInputEvent>> = HashMap::new();
+
+    for p in profiles.values() {
+        let conditions = p.mappings.iter().filter_map(|(_, m)| m.when.as_ref());
+        let inputs = p
+            .mappings
+            .iter()
+            .flat_map(|(ue, m)| m.mapping.inputs(&ue.id))
+            .chain(conditions.flat_map(|c| c.inputs()));
+
+        for ue in inputs {
+            ids.entry(ue.id).or_default().insert(ue.event);
+        }
+
+        for s in p.switch.iter() {
+            let keys = s.keys.iter().map(|k| ControllerInputEvent::Key(*k));
+            ids.entry(s.id.clone()).or_default().extend(keys);
+        }
+
+        for id in p.unmapped.keys() {
+            ids.entry(id.clone()).or_default();
+        }
+    }
+
+    ids
+}
+
+/// Controllers that any profile asks not to grab
+pub fn ungrabbed_ids(profiles: &Profiles) -> HashSet<ControllerId> {
+    profiles
+        .values()
+        .flat_map(|p| p.ungrabbed.iter().cloned())
+        .collect()
+}
+
+pub fn read(path: &String) -> Result<Profiles, FatalError> {
+    let file = File::open(path)?;
+
+    let config: Config = serde_yaml::from_reader(file)?;
+
+    let top_level =
+        !config.devices.is_empty() || !config.combine.is_empty() || !config.expressions.is_empty();
+    let profiles: Profiles = match (top_level, config.profiles.is_empty()) {
+        (true, true) => BTreeMap::from([(
+            DEFAULT_PROFILE.to_string(),
+            Profile::new(
+                config.devices,
+                config.combine,
+                config.expressions,
+                None,
+                config.shared_axes,
+            ),
+        )]),
+        (false, false) => config
+            .profiles
+            .into_iter()
+            .map(|(name, p)| {
+                let shared_axes = p.shared_axes.unwrap_or(config.shared_axes.clone());
+                let profile =
+                    Profile::new(p.devices, p.combine, p.expressions, p.switch, shared_axes);
+                (name, profile)
+            })
+            .collect(),
+        (true, false) => Err("Config must contain either 'devices' or 'profiles', not both")?,
+        (false, true) => Err("Config must contain either 'devices' or 'profiles'")?,
     };
 
+    println!("{:?}", profiles);
+    Ok(profiles)
+}
+
+fn mappings_to_map(id: ControllerId, mappings: Vec<MappingConfig>) -> ConfigMap {
     mappings
         .into_iter()
-        .map(|m| (UniqueControllerEvent::new(id.clone(), m.clone().into()), m))
+        .map(|m| {
+            (
+                UniqueControllerEvent::new(id.clone(), m.mapping.clone().into()),
+                m,
+            )
+        })
         .collect()
 }
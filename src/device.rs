@@ -4,25 +4,38 @@ use std::{
     path::PathBuf,
 };
 
+use log::warn;
+
 use crate::{
-    config::ControllerId,
+    calibration::{self, Calibrations, DeviceCalibration},
+    config::{ControllerId, ControllerInputEvent},
     error::FatalError,
     ew_device::Device,
-    ew_types::{AbsInfo, AbsoluteAxisType, KeyCode},
+    ew_types::{AbsInfo, AbsoluteAxisType, KeyCode, RelativeAxisType},
 };
 
 pub struct DeviceInfo {
     pub axis_info: HashMap<AbsoluteAxisType, AbsInfo>,
     pub key_info: HashSet<KeyCode>,
+    pub rel_info: HashSet<RelativeAxisType>,
+    /// Observed ranges replacing those in `axis_info` when building mappings
+    pub calibration: DeviceCalibration,
 }
 
-pub fn get_device_info(device: &Device) -> Result<DeviceInfo, Error> {
+pub fn get_device_info(device: &Device, calibrations: &Calibrations) -> Result<DeviceInfo, Error> {
     let key_info: HashSet<KeyCode> = device.supported_keys();
+    let rel_info = device.supported_relative_axes();
     let axis_info = device.get_abs_state()?;
+    let calibration = calibration::device_key(device)
+        .and_then(|key| calibrations.get(&key))
+        .cloned()
+        .unwrap_or_default();
 
     Ok(DeviceInfo {
         axis_info,
         key_info,
+        rel_info,
+        calibration,
     })
 }
 
@@ -70,12 +83,38 @@ fn print_properties(device: &Device) -> Result<(), Error> {
     Ok(())
 }
 
-fn find_device_by_name(name: &String) -> Result<PathBuf, FatalError> {
+/// Whether a device can send `input`
+fn supports(device: &evdev::Device, input: &ControllerInputEvent) -> bool {
+    match input {
+        ControllerInputEvent::AbsAxis(a) => device
+            .supported_absolute_axes()
+            .is_some_and(|axes| axes.contains(a.0)),
+        ControllerInputEvent::RelAxis(r) => device
+            .supported_relative_axes()
+            .is_some_and(|axes| axes.contains(r.0)),
+        ControllerInputEvent::Key(k) => device
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(k.0)),
+        ControllerInputEvent::Synchronization(_) => true,
+    }
+}
+
+/// Find a device by name, using the inputs it needs to support to choose between devices
+/// with the same name such as the keyboard and mouse of a wireless receiver
+fn find_device_by_name(
+    name: &String,
+    inputs: &HashSet<ControllerInputEvent>,
+) -> Result<PathBuf, FatalError> {
     let devices = evdev::enumerate().collect::<HashMap<_, _>>();
-    let devs_with_name: HashMap<_, _> = devices
+    let mut devs_with_name: HashMap<_, _> = devices
         .iter()
         .filter(|(_, d)| d.name().unwrap_or("") == name)
         .collect();
+
+    if devs_with_name.len() > 1 {
+        devs_with_name.retain(|_, d| inputs.iter().all(|i| supports(d, i)));
+    }
+
     match devs_with_name.len() {
         1 => {
             let path: Vec<&PathBuf> = devs_with_name.into_keys().collect();
@@ -83,26 +122,39 @@ fn find_device_by_name(name: &String) -> Result<PathBuf, FatalError> {
             Ok(path[0].to_owned())
         }
         0 => Err(FatalError::from(format!(
-            "No device with name {:?} found",
-            name
+            "No device with name {:?} and inputs {:?} found",
+            name, inputs
         ))),
         _ => Err(FatalError::from(format!(
-            "Too many devices with name {:?} found",
-            name
+            "Too many devices with name {:?} found, use one of these paths instead {:?}",
+            name,
+            devs_with_name.into_keys().collect::<Vec<_>>()
         ))),
     }
 }
 
-pub fn open_device(id: &ControllerId) -> Result<Device, FatalError> {
+pub fn open_device(
+    id: &ControllerId,
+    inputs: &HashSet<ControllerInputEvent>,
+    grab: bool,
+) -> Result<Device, FatalError> {
     let path = match id {
         ControllerId::Path(path) => path.clone(),
-        ControllerId::Name(name) => find_device_by_name(name)?,
+        ControllerId::Name(name) => find_device_by_name(name, inputs)?,
     };
 
     let mut device = Device::open(path)?;
 
     // Grab the device to stop duplicate events from multiple devices
-    device.grab()?;
+    if grab {
+        device.grab()?;
+    } else {
+        warn!(
+            "Not grabbing {:?}, programs reading it directly will see its events as well as \
+            those of the virtual device so they may act on every input twice",
+            id
+        );
+    }
 
     print_properties(&device)?;
     Ok(device)
@@ -64,3 +64,18 @@ impl From<String> for NonFatalError {
         NonFatalError::Str(err)
     }
 }
+
+impl Display for NonFatalError {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            Self::Io(e) => Display::fmt(e, f),
+            Self::Str(e) => Display::fmt(e, f),
+        }
+    }
+}
+
+impl From<io::Error> for NonFatalError {
+    fn from(err: io::Error) -> NonFatalError {
+        NonFatalError::Io(err)
+    }
+}
@@ -4,7 +4,7 @@ use std::{
     path::Path,
 };
 
-use crate::ew_types::{AbsInfo, AbsoluteAxisType, EventStream, KeyCode};
+use crate::ew_types::{AbsInfo, AbsoluteAxisType, EventStream, KeyCode, RelativeAxisType};
 
 pub struct Device(evdev::Device);
 
@@ -46,6 +46,10 @@ impl Device {
         self.0.name()
     }
 
+    pub fn physical_path(&self) -> Option<&str> {
+        self.0.physical_path()
+    }
+
     pub fn supported_keys(&self) -> HashSet<KeyCode> {
         let mut key_info: HashSet<KeyCode> = HashSet::new();
         if let Some(key_attrs) = self.0.supported_keys() {
@@ -55,6 +59,15 @@ impl Device {
         key_info
     }
 
+    pub fn supported_relative_axes(&self) -> HashSet<RelativeAxisType> {
+        let mut rel_info: HashSet<RelativeAxisType> = HashSet::new();
+        if let Some(rel_attrs) = self.0.supported_relative_axes() {
+            rel_info = rel_attrs.iter().map(RelativeAxisType).collect();
+        }
+
+        rel_info
+    }
+
     pub fn grab(&mut self) -> Result<(), Error> {
         self.0.grab()
     }
@@ -1,10 +1,10 @@
 use evdev::EventType;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct AbsoluteAxisType(pub evdev::AbsoluteAxisCode);
 
 impl Deref for AbsoluteAxisType {
@@ -29,6 +29,31 @@ impl PartialEq for AbsoluteAxisType {
     }
 }
 
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct RelativeAxisType(pub evdev::RelativeAxisCode);
+
+impl Deref for RelativeAxisType {
+    type Target = evdev::RelativeAxisCode;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Eq for RelativeAxisType {}
+
+impl Hash for RelativeAxisType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0 .0.hash(state)
+    }
+}
+
+impl PartialEq for RelativeAxisType {
+    fn eq(&self, other: &RelativeAxisType) -> bool {
+        self.0 == other.0
+    }
+}
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub struct KeyCode(pub evdev::KeyCode);
 
@@ -68,6 +93,25 @@ impl AbsInfo {
             self.0.resolution(),
         ))
     }
+
+    /// The current value scaled from min..max to -1.0..1.0
+    pub fn normalised(&self) -> f64 {
+        let min = self.0.minimum() as f64;
+        let max = self.0.maximum() as f64;
+        if max <= min {
+            return 0.0;
+        }
+
+        2.0 * (self.0.value() as f64 - min) / (max - min) - 1.0
+    }
+
+    /// Set the value from -1.0..1.0 scaled to min..max
+    pub fn clone_set_normalised(&self, value: f64) -> Self {
+        let min = self.0.minimum() as f64;
+        let max = self.0.maximum() as f64;
+        let scaled = min + (value.clamp(-1.0, 1.0) + 1.0) / 2.0 * (max - min);
+        self.clone_set_value(scaled.round() as i32)
+    }
 }
 
 impl fmt::Debug for AbsInfo {
@@ -117,4 +161,8 @@ impl EventStream {
         let result = self.0.next_event().await?;
         Ok(InputEvent(result))
     }
+
+    pub fn ungrab(&mut self) -> Result<(), std::io::Error> {
+        self.0.device_mut().ungrab()
+    }
 }
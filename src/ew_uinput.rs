@@ -2,49 +2,93 @@ use std::{io::Error, path::PathBuf};
 
 use evdev::{uinput, AttributeSet, UinputAbsSetup};
 
-use crate::output_event::OutputEvent;
+use crate::output_event::{OutputDevice, OutputEvent};
 
-pub struct VirtualDevice(uinput::VirtualDevice);
+pub struct VirtualDevice(uinput::VirtualDevice, Capabilities);
 
-fn make_uniput_config(
-    output_actions: Vec<&OutputEvent>,
-) -> (Vec<UinputAbsSetup>, AttributeSet<evdev::KeyCode>) {
+/// The axes and keys a virtual device was built with
+#[derive(PartialEq)]
+struct Capabilities {
+    kind: OutputDevice,
+    all_axis: Vec<UinputAbsSetup>,
+    rel_axis: Vec<evdev::RelativeAxisCode>,
+    keys: Vec<evdev::KeyCode>,
+}
+
+fn make_uniput_config(kind: OutputDevice, output_actions: Vec<&OutputEvent>) -> Capabilities {
     // Need to build a list of all keys to pass to the builder
     // so we may as well extract the axis too
     let mut all_axis: Vec<UinputAbsSetup> = Vec::new();
+    let mut rel_axis: AttributeSet<evdev::RelativeAxisCode> = AttributeSet::new();
     let mut keys: AttributeSet<evdev::KeyCode> = AttributeSet::new();
-    for event in output_actions {
-        match event {
-            OutputEvent::AbsAxis(a) => {
-                let abs = UinputAbsSetup::new(a.axis_type.0, a.axis_info.0);
-                all_axis.push(abs)
-            }
-            OutputEvent::Key(a) => keys.insert(a.code().0),
-            OutputEvent::Synchronization(_) => (),
-            OutputEvent::FilteredAbsAxis(f) => {
-                for item in f.codes() {
-                    keys.insert(item.0)
-                }
+
+    if kind == OutputDevice::Mouse {
+        // Needed for the device to be treated as a mouse
+        keys.insert(evdev::KeyCode::BTN_LEFT);
+        keys.insert(evdev::KeyCode::BTN_RIGHT);
+        keys.insert(evdev::KeyCode::BTN_MIDDLE);
+    }
+
+    if kind == OutputDevice::Keyboard {
+        // Needed for the device to be treated as a keyboard, KEY_ESC to KEY_D
+        for code in 1..32 {
+            keys.insert(evdev::KeyCode(code));
+        }
+    }
+
+    for event in output_actions.into_iter().filter(|e| e.device() == kind) {
+        for (axis_type, axis_info) in event.abs_axes() {
+            // Shared axes are scaled to the range of the first output writing them
+            if all_axis.iter().all(|a| a.code() != axis_type.0 .0) {
+                all_axis.push(UinputAbsSetup::new(axis_type.0, axis_info.0))
             }
         }
+        for axis in event.rel_axes() {
+            rel_axis.insert(axis.0)
+        }
+        for key in event.keys() {
+            keys.insert(key.0)
+        }
     }
 
-    (all_axis, keys)
+    all_axis.sort_by_key(|a| a.code());
+
+    Capabilities {
+        kind,
+        all_axis,
+        rel_axis: rel_axis.iter().collect(),
+        keys: keys.iter().collect(),
+    }
 }
 
-fn build_device(
-    all_axis: Vec<UinputAbsSetup>,
-    keys: AttributeSet<evdev::KeyCode>,
-) -> Result<VirtualDevice, Error> {
+fn device_name(kind: OutputDevice) -> &'static str {
+    match kind {
+        OutputDevice::Gamepad => "evdev-mapper gamepad",
+        OutputDevice::Mouse => "evdev-mapper mouse",
+        OutputDevice::Keyboard => "evdev-mapper keyboard",
+    }
+}
+
+fn build_device(capabilities: Capabilities) -> Result<VirtualDevice, Error> {
+    let keys: AttributeSet<evdev::KeyCode> = capabilities.keys.iter().copied().collect();
+
     let builder = uinput::VirtualDevice::builder()?;
-    let mut builder = builder.name("evdev-mapper gamepad").with_keys(&keys)?;
+    let mut builder = builder
+        .name(device_name(capabilities.kind))
+        .with_keys(&keys)?;
+
+    if !capabilities.rel_axis.is_empty() {
+        let rel_axis: AttributeSet<evdev::RelativeAxisCode> =
+            capabilities.rel_axis.iter().copied().collect();
+        builder = builder.with_relative_axes(&rel_axis)?;
+    }
 
-    for axis in all_axis {
-        builder = builder.with_absolute_axis(&axis)?;
+    for axis in capabilities.all_axis.iter() {
+        builder = builder.with_absolute_axis(axis)?;
     }
 
     let device = builder.build()?;
-    Ok(VirtualDevice(device))
+    Ok(VirtualDevice(device, capabilities))
 }
 
 fn wrangle_output_event(event: &OutputEvent) -> Vec<evdev::InputEvent> {
@@ -52,9 +96,13 @@ fn wrangle_output_event(event: &OutputEvent) -> Vec<evdev::InputEvent> {
 }
 
 impl VirtualDevice {
-    pub fn new(output_events: Vec<&OutputEvent>) -> Result<Self, Error> {
-        let (abs_axis, buttons) = make_uniput_config(output_events);
-        build_device(abs_axis, buttons)
+    pub fn new(kind: OutputDevice, output_events: Vec<&OutputEvent>) -> Result<Self, Error> {
+        build_device(make_uniput_config(kind, output_events))
+    }
+
+    /// Whether this device was built with exactly the axes and keys needed by `output_events`
+    pub fn has_capabilities(&self, output_events: Vec<&OutputEvent>) -> bool {
+        self.1 == make_uniput_config(self.1.kind, output_events)
     }
 
     pub fn enumerate_dev_nodes_blocking(&mut self) -> Result<Vec<PathBuf>, Error> {
@@ -68,9 +116,41 @@ impl VirtualDevice {
         Ok(paths)
     }
 
+    fn key_releases(&self) -> impl Iterator<Item = evdev::InputEvent> + '_ {
+        self.1
+            .keys
+            .iter()
+            .map(|k| evdev::InputEvent::new(evdev::EventType::KEY.0, k.0, 0))
+    }
+
+    /// Release every key, leaving the axes where they are
+    pub fn release_keys(&mut self) -> Result<(), Error> {
+        let evdev_events: Vec<evdev::InputEvent> = self.key_releases().collect();
+        self.0.emit(&evdev_events)
+    }
+
+    /// Release every key and centre every axis so nothing is left held
+    pub fn release_all(&mut self) -> Result<(), Error> {
+        let keys = self.key_releases();
+        let axes = self.1.all_axis.iter().map(|a| {
+            let info = a.absinfo();
+            let centre = info.minimum() + (info.maximum() - info.minimum()) / 2;
+            evdev::InputEvent::new(evdev::EventType::ABSOLUTE.0, a.code(), centre)
+        });
+
+        let evdev_events: Vec<evdev::InputEvent> = keys.chain(axes).collect();
+        self.0.emit(&evdev_events)
+    }
+
     pub fn emit(&mut self, events: &[OutputEvent]) -> Result<(), Error> {
         let evdev_events: Vec<evdev::InputEvent> =
             events.iter().flat_map(wrangle_output_event).collect();
+
+        // Nothing changed so don't send an empty report
+        if evdev_events.is_empty() {
+            return Ok(());
+        }
+
         self.0.emit(&evdev_events)
     }
 }
@@ -0,0 +1,493 @@
+use std::{fmt, iter::Peekable, str::CharIndices};
+
+/// Types of values in an expression, checked when it is parsed
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Bool => write!(f, "true / false value"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    /// Binding strength, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Equal | BinaryOp::NotEqual => 3,
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply | BinaryOp::Divide => 6,
+        }
+    }
+
+    /// Types of the operands and the result
+    fn types(self) -> (Type, Type) {
+        match self {
+            BinaryOp::Add | BinaryOp::Subtract | BinaryOp::Multiply | BinaryOp::Divide => {
+                (Type::Number, Type::Number)
+            }
+            BinaryOp::And | BinaryOp::Or => (Type::Bool, Type::Bool),
+            _ => (Type::Number, Type::Bool),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+    Clamp,
+    If,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "clamp" => Some(Function::Clamp),
+            "if" => Some(Function::If),
+            _ => None,
+        }
+    }
+
+    fn arguments(self) -> &'static [Type] {
+        match self {
+            Function::Abs => &[Type::Number],
+            Function::Min | Function::Max => &[Type::Number, Type::Number],
+            Function::Clamp => &[Type::Number, Type::Number, Type::Number],
+            Function::If => &[Type::Bool, Type::Number, Type::Number],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(f64),
+    Bool(bool),
+    /// Index of a named input
+    Input(usize),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    fn number(&self, inputs: &[f64]) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Input(i) => inputs[*i],
+            Expr::Negate(e) => -e.number(inputs),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.number(inputs), b.number(inputs));
+                match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Subtract => a - b,
+                    BinaryOp::Multiply => a * b,
+                    BinaryOp::Divide => a / b,
+                    _ => unreachable!("{:?} is not a number", op),
+                }
+            }
+            Expr::Call(f, args) => match f {
+                Function::Abs => args[0].number(inputs).abs(),
+                Function::Min => args[0].number(inputs).min(args[1].number(inputs)),
+                Function::Max => args[0].number(inputs).max(args[1].number(inputs)),
+                Function::Clamp => {
+                    let (min, max) = (args[1].number(inputs), args[2].number(inputs));
+                    args[0].number(inputs).max(min).min(max)
+                }
+                Function::If => match args[0].bool(inputs) {
+                    true => args[1].number(inputs),
+                    false => args[2].number(inputs),
+                },
+            },
+            Expr::Bool(_) | Expr::Not(_) => unreachable!("{:?} is not a number", self),
+        }
+    }
+
+    fn bool(&self, inputs: &[f64]) -> bool {
+        match self {
+            Expr::Bool(b) => *b,
+            Expr::Not(e) => !e.bool(inputs),
+            Expr::Binary(BinaryOp::And, a, b) => a.bool(inputs) && b.bool(inputs),
+            Expr::Binary(BinaryOp::Or, a, b) => a.bool(inputs) || b.bool(inputs),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.number(inputs), b.number(inputs));
+                match op {
+                    BinaryOp::Less => a < b,
+                    BinaryOp::LessEqual => a <= b,
+                    BinaryOp::Greater => a > b,
+                    BinaryOp::GreaterEqual => a >= b,
+                    BinaryOp::Equal => a == b,
+                    BinaryOp::NotEqual => a != b,
+                    _ => unreachable!("{:?} is not a bool", op),
+                }
+            }
+            _ => unreachable!("{:?} is not a bool", self),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Op(&'static str),
+    End,
+}
+
+const OPERATORS: [&str; 15] = [
+    "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "<", ">", "!", "(", ")",
+];
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    names: &'a [String],
+    /// Next token and where it starts
+    token: Token,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, names: &'a [String]) -> Result<Self, String> {
+        let mut parser = Parser {
+            source,
+            chars: source.char_indices().peekable(),
+            names,
+            token: Token::End,
+            position: 0,
+        };
+        parser.advance()?;
+        Ok(parser)
+    }
+
+    fn error(&self, message: String) -> String {
+        format!(
+            "{} at position {} of '{}'",
+            message, self.position, self.source
+        )
+    }
+
+    /// Move on to the next token
+    fn advance(&mut self) -> Result<(), String> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+
+        let Some(&(start, c)) = self.chars.peek() else {
+            self.position = self.source.len();
+            self.token = Token::End;
+            return Ok(());
+        };
+        self.position = start;
+
+        let take_while = |chars: &mut Peekable<CharIndices>, f: fn(char) -> bool| {
+            let mut end = start;
+            while let Some((i, c)) = chars.next_if(|(_, c)| f(*c)) {
+                end = i + c.len_utf8();
+            }
+            end
+        };
+
+        self.token = if c.is_ascii_digit() || c == '.' {
+            let end = take_while(&mut self.chars, |c| c.is_ascii_digit() || c == '.');
+            let text = &self.source[start..end];
+            Token::Number(
+                text.parse()
+                    .map_err(|_| self.error(format!("Invalid number '{}'", text)))?,
+            )
+        } else if c.is_alphabetic() || c == '_' {
+            let end = take_while(&mut self.chars, |c| c.is_alphanumeric() || c == '_');
+            Token::Name(self.source[start..end].to_string())
+        } else if c == ',' {
+            self.chars.next();
+            Token::Op(",")
+        } else {
+            let rest = &self.source[start..];
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| self.error(format!("Unexpected '{}'", c)))?;
+            for _ in 0..op.len() {
+                self.chars.next();
+            }
+            Token::Op(op)
+        };
+
+        Ok(())
+    }
+
+    fn expect(&mut self, op: &'static str) -> Result<(), String> {
+        match self.token == Token::Op(op) {
+            true => self.advance(),
+            false => Err(self.error(format!("Expected '{}'", op))),
+        }
+    }
+
+    fn check(&self, found: Type, expected: Type, what: &str) -> Result<(), String> {
+        match found == expected {
+            true => Ok(()),
+            false => Err(self.error(format!(
+                "{} must be a {} but is a {}",
+                what, expected, found
+            ))),
+        }
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        let op = match self.token {
+            Token::Op("+") => BinaryOp::Add,
+            Token::Op("-") => BinaryOp::Subtract,
+            Token::Op("*") => BinaryOp::Multiply,
+            Token::Op("/") => BinaryOp::Divide,
+            Token::Op("<") => BinaryOp::Less,
+            Token::Op("<=") => BinaryOp::LessEqual,
+            Token::Op(">") => BinaryOp::Greater,
+            Token::Op(">=") => BinaryOp::GreaterEqual,
+            Token::Op("==") => BinaryOp::Equal,
+            Token::Op("!=") => BinaryOp::NotEqual,
+            Token::Op("&&") => BinaryOp::And,
+            Token::Op("||") => BinaryOp::Or,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// Binary operators binding at least as tightly as `min_precedence`
+    fn expression(&mut self, min_precedence: u8) -> Result<(Expr, Type), String> {
+        let (mut lhs, mut lhs_type) = self.unary()?;
+
+        while let Some(op) = self
+            .binary_op()
+            .filter(|op| op.precedence() >= min_precedence)
+        {
+            let (operands, result) = op.types();
+            self.check(lhs_type, operands, &format!("Left of {:?}", op))?;
+            self.advance()?;
+
+            let (rhs, rhs_type) = self.expression(op.precedence() + 1)?;
+            self.check(rhs_type, operands, &format!("Right of {:?}", op))?;
+
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+            lhs_type = result;
+        }
+
+        Ok((lhs, lhs_type))
+    }
+
+    fn unary(&mut self) -> Result<(Expr, Type), String> {
+        match self.token {
+            Token::Op("-") => {
+                self.advance()?;
+                let (e, t) = self.unary()?;
+                self.check(t, Type::Number, "Negated value")?;
+                Ok((Expr::Negate(Box::new(e)), Type::Number))
+            }
+            Token::Op("!") => {
+                self.advance()?;
+                let (e, t) = self.unary()?;
+                self.check(t, Type::Bool, "Value of '!'")?;
+                Ok((Expr::Not(Box::new(e)), Type::Bool))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<(Expr, Type), String> {
+        match self.token.clone() {
+            Token::Number(n) => {
+                self.advance()?;
+                Ok((Expr::Number(n), Type::Number))
+            }
+            Token::Op("(") => {
+                self.advance()?;
+                let inner = self.expression(0)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Token::Name(name) => match self.names.iter().position(|n| *n == name) {
+                Some(index) => {
+                    self.advance()?;
+                    Ok((Expr::Input(index), Type::Number))
+                }
+                None if name == "true" || name == "false" => {
+                    self.advance()?;
+                    Ok((Expr::Bool(name == "true"), Type::Bool))
+                }
+                None => self.call(name),
+            },
+            Token::Op(op) => Err(self.error(format!("Unexpected '{}'", op))),
+            Token::End => Err(self.error("Unexpected end".to_string())),
+        }
+    }
+
+    fn call(&mut self, name: String) -> Result<(Expr, Type), String> {
+        let function = Function::from_name(&name)
+            .ok_or_else(|| self.error(format!("Unknown input or function '{}'", name)))?;
+        self.advance()?;
+        self.expect("(")?;
+
+        let mut args = Vec::new();
+        for (i, expected) in function.arguments().iter().enumerate() {
+            if i > 0 {
+                self.expect(",")?;
+            }
+            let (arg, found) = self.expression(0)?;
+            self.check(found, *expected, &format!("Argument {} of {}", i + 1, name))?;
+            args.push(arg);
+        }
+        self.expect(")")?;
+
+        Ok((Expr::Call(function, args), Type::Number))
+    }
+}
+
+/// An arithmetic expression over named inputs, checked to give a number when parsed
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    expr: Expr,
+}
+
+impl Expression {
+    /// Parse `source` where `names` are the inputs that can be used, in the order
+    /// their values are given to `evaluate`
+    pub fn parse(source: &str, names: &[String]) -> Result<Self, String> {
+        let mut parser = Parser::new(source, names)?;
+        let (expr, found) = parser.expression(0)?;
+        if parser.token != Token::End {
+            Err(parser.error("Expected the end".to_string()))?;
+        }
+        parser.check(found, Type::Number, "The expression")?;
+
+        Ok(Expression {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn evaluate(&self, inputs: &[f64]) -> f64 {
+        self.expr.number(inputs)
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expression({:?})", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn evaluate(source: &str, inputs: &[(&str, f64)]) -> f64 {
+        let (input_names, values): (Vec<&str>, Vec<f64>) = inputs.iter().copied().unzip();
+        Expression::parse(source, &names(&input_names))
+            .unwrap()
+            .evaluate(&values)
+    }
+
+    fn error(source: &str, inputs: &[&str]) -> String {
+        Expression::parse(source, &names(inputs)).unwrap_err()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3", &[]), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3", &[]), 9.0);
+        assert_eq!(evaluate("if(1 + 1 > 1 && 2 < 1 || true, 1, 0)", &[]), 1.0);
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(evaluate("8 - 4 - 2", &[]), 2.0);
+        assert_eq!(evaluate("8 / 4 / 2", &[]), 1.0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(evaluate("-2 * 3", &[]), -6.0);
+        assert_eq!(evaluate("2 - -3", &[]), 5.0);
+        assert_eq!(evaluate("-(1 + a)", &[("a", 0.5)]), -1.5);
+        assert_eq!(evaluate("abs(-a)", &[("a", 0.5)]), 0.5);
+    }
+
+    #[test]
+    fn type_errors() {
+        let e = error("(a < b) + 1", &["a", "b"]);
+        assert!(e.contains("Left of Add must be a number"), "{}", e);
+        let e = error("a < b", &["a", "b"]);
+        assert!(e.contains("The expression must be a number"), "{}", e);
+        let e = error("if(!1, 0, 1)", &[]);
+        assert!(
+            e.contains("Value of '!' must be a true / false value"),
+            "{}",
+            e
+        );
+        let e = error("if(a, 0, 1)", &["a"]);
+        assert!(
+            e.contains("Argument 1 of if must be a true / false value"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn unknown_names() {
+        let e = error("a + c", &["a", "b"]);
+        assert!(e.contains("Unknown input or function 'c'"), "{}", e);
+        let e = error("sqrt(a)", &["a"]);
+        assert!(e.contains("Unknown input or function 'sqrt'"), "{}", e);
+    }
+
+    #[test]
+    fn trailing_input() {
+        let e = error("a b", &["a", "b"]);
+        assert!(e.contains("Expected the end"), "{}", e);
+        let e = error("min(a, b))", &["a", "b"]);
+        assert!(e.contains("Expected the end"), "{}", e);
+        let e = error("a +", &["a"]);
+        assert!(e.contains("Unexpected end"), "{}", e);
+    }
+
+    #[test]
+    fn readme_example() {
+        let source = "clamp(throttle_l * 0.5 + throttle_r * 0.5, 0, 1)";
+        let inputs = [("throttle_l", 0.4), ("throttle_r", 0.8)];
+        assert!((evaluate(source, &inputs) - 0.6).abs() < 1e-9);
+        let inputs = [("throttle_l", 1.0), ("throttle_r", 1.0)];
+        assert_eq!(evaluate(source, &inputs), 1.0);
+    }
+}
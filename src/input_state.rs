@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    config::{Condition, ControllerId, ControllerInputEvent, UniqueControllerEvent},
+    device::DeviceInfo,
+};
+
+/// The last value of every input from every device and the active layers
+#[derive(Debug, Default)]
+pub struct InputState {
+    values: HashMap<UniqueControllerEvent, i32>,
+    layers: HashSet<String>,
+}
+
+impl InputState {
+    /// Start with the axes where the devices report them, buttons are released
+    pub fn new(id_and_info: &HashMap<ControllerId, DeviceInfo>) -> Self {
+        let values = id_and_info
+            .iter()
+            .flat_map(|(id, info)| {
+                info.axis_info.iter().map(|(axis, axis_info)| {
+                    let input = ControllerInputEvent::AbsAxis(*axis);
+                    (
+                        UniqueControllerEvent::new(id.clone(), input),
+                        axis_info.0.value(),
+                    )
+                })
+            })
+            .collect();
+
+        InputState {
+            values,
+            layers: HashSet::new(),
+        }
+    }
+
+    pub fn value(&self, input: &UniqueControllerEvent) -> i32 {
+        self.values.get(input).copied().unwrap_or_default()
+    }
+
+    pub fn set_value(&mut self, input: UniqueControllerEvent, value: i32) {
+        self.values.insert(input, value);
+    }
+
+    /// Every input that has reported a value
+    pub fn values(&self) -> impl Iterator<Item = (&UniqueControllerEvent, i32)> {
+        self.values.iter().map(|(input, value)| (input, *value))
+    }
+
+    /// The same input values with no layers active, layers belong to a profile
+    pub fn without_layers(&self) -> Self {
+        InputState {
+            values: self.values.clone(),
+            layers: HashSet::new(),
+        }
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = &String> {
+        self.layers.iter()
+    }
+
+    pub fn set_layer(&mut self, layer: &str, active: bool) {
+        match active {
+            true => self.layers.insert(layer.to_string()),
+            false => self.layers.remove(layer),
+        };
+    }
+
+    pub fn check(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::Held { held } => self.value(&held.input()) != 0,
+            Condition::Axis { axis, above, below } => {
+                let value = self.value(&axis.input());
+                above.is_none_or(|a| value > a) && below.is_none_or(|b| value < b)
+            }
+            Condition::Layer { layer } => self.layers.contains(layer),
+            Condition::Not { not } => !self.check(not),
+            Condition::All { all } => all.iter().all(|c| self.check(c)),
+        }
+    }
+}
@@ -1,30 +1,41 @@
 mod args;
+mod calibration;
 mod config;
 mod device;
 mod error;
 mod ew_device;
 mod ew_types;
 mod ew_uinput;
+mod expression;
+mod input_state;
 mod mapping;
 mod output_event;
+mod profile;
+mod script;
+mod trim_file;
 mod uinput;
 mod util;
 
 use args::Mode;
 use clap::Parser;
-use config::{ConfigMap, ControllerId};
+use config::{ControllerId, Profiles};
+use device::{get_device_info, DeviceInfo};
 use error::FatalError;
 use ew_device::Device;
 use ew_types::{EventStream, InputEvent};
-use ew_uinput::VirtualDevice;
 use futures::stream::{FuturesUnordered, StreamExt};
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use profile::ProfileSwitcher;
 use std::collections::HashMap;
 use std::error::Error;
+use std::path::Path;
+use std::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::sleep_until;
 use util::rewrap;
 
 use mapping::EventMapping;
-use uinput::new_device;
+use uinput::OutputDevices;
 
 use crate::error::NonFatalError;
 
@@ -35,6 +46,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let args = args::Args::parse();
     let mode = args.mode;
     let config_path = args.config;
+    let profile = args.profile;
+    let calibration_path = args.calibration;
+    let no_grab = args.no_grab;
 
     match mode {
         Mode::Devices => {
@@ -48,11 +62,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
             Ok(())
         }
+        Mode::Calibrate => {
+            match args.device {
+                Some(device_path) => calibration::calibrate(device_path, &calibration_path).await?,
+                None => error!("Device must be set in 'calibrate' mode."),
+            }
+            Ok(())
+        }
         Mode::Run => {
             let config = config::read(&config_path);
             match config {
                 Ok(c) => {
-                    run(c).await?;
+                    run(c, profile, &calibration_path, no_grab).await?;
                 }
                 Err(e) => {
                     error!("Failed to read config file '{:}'. {:}.", config_path, e);
@@ -64,19 +85,55 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
-async fn run(config: ConfigMap) -> Result<(), Box<dyn Error>> {
-    let paths_and_devs_or_error: Result<HashMap<ControllerId, Device>, FatalError> = config
-        .keys()
-        .map(|ue| rewrap(ue.id.clone(), device::open_device(&ue.id)))
-        .collect();
+async fn run(
+    profiles: Profiles,
+    profile: Option<String>,
+    calibration_path: &Path,
+    no_grab: bool,
+) -> Result<(), Box<dyn Error>> {
+    let ungrabbed = config::ungrabbed_ids(&profiles);
+    let paths_and_devs_or_error: Result<HashMap<ControllerId, Device>, FatalError> =
+        config::controller_ids(&profiles)
+            .into_iter()
+            .map(|(id, inputs)| {
+                let grab = !no_grab && !ungrabbed.contains(&id);
+                let device = device::open_device(&id, &inputs, grab);
+                rewrap(id, device)
+            })
+            .collect();
 
     let paths_and_devs = paths_and_devs_or_error?;
 
-    let mappings = EventMapping::new(config, &paths_and_devs)?;
+    let calibrations = calibration::load(calibration_path)?;
+
+    let id_and_info_or_error: Result<HashMap<_, _>, std::io::Error> = paths_and_devs
+        .iter()
+        .map(|(id, d)| rewrap(id.clone(), get_device_info(d, &calibrations)))
+        .collect();
 
-    let output_device = new_device(&mappings)?;
+    let id_and_info = id_and_info_or_error?;
+
+    // Catch mistakes in every profile now rather than when switching to it
+    for (name, p) in profiles.iter() {
+        EventMapping::new(p, &id_and_info)
+            .map_err(|e| format!("Profile {:?} is invalid. {}", name, e))?;
+    }
 
-    combine_devices(paths_and_devs, mappings, output_device).await
+    let switcher = ProfileSwitcher::new(profiles, profile, &id_and_info)?;
+    info!("Starting with profile {:?}", switcher.active());
+
+    let mappings = EventMapping::new(switcher.active_profile(), &id_and_info)?;
+
+    let output_devices = OutputDevices::new(&mappings)?;
+
+    combine_devices(
+        paths_and_devs,
+        id_and_info,
+        switcher,
+        mappings,
+        output_devices,
+    )
+    .await
 }
 
 fn make_stream(
@@ -89,8 +146,10 @@ fn make_stream(
 
 async fn combine_devices(
     devices: HashMap<ControllerId, Device>,
-    mappings: EventMapping,
-    mut output_device: VirtualDevice,
+    id_and_info: HashMap<ControllerId, DeviceInfo>,
+    mut switcher: ProfileSwitcher,
+    mut mappings: EventMapping,
+    mut output_devices: OutputDevices,
 ) -> Result<(), Box<dyn Error>> {
     // Setup event streams
     let streams_or_error: Result<HashMap<_, _>, _> = devices
@@ -100,29 +159,104 @@ async fn combine_devices(
 
     let mut streams = streams_or_error?;
 
+    // SIGUSR1 cycles through the profiles
+    let mut next_profile_signal = signal(SignalKind::user_defined1())?;
+
+    // SIGINT and SIGTERM stop cleanly
+    let mut interrupt_signal = signal(SignalKind::interrupt())?;
+    let mut terminate_signal = signal(SignalKind::terminate())?;
+
     loop {
         // Setup futures for the event sources
         let mut futures = FuturesUnordered::from_iter(
             streams.iter_mut().map(|(p, s)| next_event_with_meta(p, s)),
         );
 
-        let event = futures.next().await;
-        let result = match event {
-            // Futures.next returned something that was ok
-            Some(Ok((id, event))) => process_single_event(id, event, &mappings, &mut output_device),
+        let mut switch_to = None;
 
-            // Futures.next returned something that was an error
-            Some(Err(e)) => Err(e)?,
+        // Wake up for mappings that change over time even without input
+        let deadline = mappings.next_deadline();
 
-            // Futures.next returned nothing
-            None => Ok(()),
+        let result = tokio::select! {
+            event = futures.next() => match event {
+                // Futures.next returned something that was ok
+                Some(Ok((id, event))) => {
+                    switch_to = switcher.process(&id, &event);
+                    process_single_event(id, event, &mut mappings, &mut output_devices)
+                }
+
+                // Futures.next returned something that was an error
+                Some(Err(e)) => Err(e)?,
+
+                // Futures.next returned nothing
+                None => Ok(()),
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                process_timed_events(&mut mappings, &mut output_devices)
+            }
+            _ = next_profile_signal.recv() => {
+                switch_to = Some(switcher.next_profile());
+                Ok(())
+            }
+            _ = interrupt_signal.recv() => break,
+            _ = terminate_signal.recv() => break,
         };
 
         match result {
             Ok(_) => (),
-            Err(e) => warn!("{:?}", e),
+            Err(e) => warn!("{}", e),
         };
+
+        if let Some(name) = switch_to {
+            match switch_profile(
+                name,
+                &id_and_info,
+                &mut switcher,
+                &mappings,
+                &mut output_devices,
+            ) {
+                Ok(m) => mappings = m,
+                Err(e) => error!("Failed to switch profile. {}", e),
+            }
+        }
+    }
+
+    info!("Shutting down");
+
+    // Games would otherwise see whatever was held when stopped
+    output_devices.release_all()?;
+
+    for (id, stream) in streams.iter_mut() {
+        // Devices that weren't grabbed fail to ungrab
+        if let Err(e) = stream.ungrab() {
+            debug!("Failed to ungrab {:?}. {}", id, e);
+        }
     }
+
+    Ok(())
+}
+
+/// Build the mappings for a profile, rebuilding virtual devices only if
+/// the new profile needs different axes or keys
+fn switch_profile(
+    name: String,
+    id_and_info: &HashMap<ControllerId, DeviceInfo>,
+    switcher: &mut ProfileSwitcher,
+    previous: &EventMapping,
+    output_devices: &mut OutputDevices,
+) -> Result<EventMapping, FatalError> {
+    let mut mappings = EventMapping::new(switcher.profile(&name), id_and_info)?;
+
+    // Axes stay where the inputs are rather than where they were when the devices were opened
+    let events = mappings.resume(previous.state(), Instant::now());
+
+    // Devices kept for the new profile would otherwise hold the old profile's buttons
+    output_devices.release_keys()?;
+    output_devices.update(&mappings)?;
+    output_devices.emit(&events)?;
+
+    switcher.set_active(name);
+    Ok(mappings)
 }
 
 async fn next_event_with_meta(
@@ -136,10 +270,19 @@ async fn next_event_with_meta(
 fn process_single_event(
     id: ControllerId,
     input_event: InputEvent,
-    mappings: &EventMapping,
-    device: &mut VirtualDevice,
+    mappings: &mut EventMapping,
+    device: &mut OutputDevices,
+) -> Result<(), NonFatalError> {
+    let events = mappings.get_output_event(id, input_event, Instant::now())?;
+    debug!("writing events {:?}", events);
+    device.emit(&events).map_err(NonFatalError::Io)
+}
+
+fn process_timed_events(
+    mappings: &mut EventMapping,
+    device: &mut OutputDevices,
 ) -> Result<(), NonFatalError> {
-    let event = mappings.get_output_event(id, input_event)?;
-    debug!("writing event {:?}", event);
-    device.emit(&[event]).map_err(NonFatalError::Io)
+    let events = mappings.get_timed_events(Instant::now());
+    debug!("writing timed events {:?}", events);
+    device.emit(&events).map_err(NonFatalError::Io)
 }
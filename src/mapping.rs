@@ -1,27 +1,122 @@
 use crate::{
-    config::{self, ConfigMap, ControllerId, ControllerInputEvent, UniqueControllerEvent},
-    device::{get_device_info, DeviceInfo},
+    calibration::AxisCalibration,
+    config::{
+        self, AxisPair, Condition, ControllerId, ControllerInputEvent, OutputRange, Profile,
+        SharedAxisPolicy, UniqueControllerEvent, UnmappedPolicy,
+    },
+    device::DeviceInfo,
     error::{FatalError, NonFatalError},
-    ew_device::Device,
-    ew_types::{AbsoluteAxisType, InputEvent, Synchronization},
+    ew_types::{AbsInfo, AbsoluteAxisType, InputEvent, Synchronization},
+    input_state::InputState,
     output_event::{
-        AbsAxisOutputEvent, FilteredAbsAxisOutputEvent, KeyOutputEvent, OutputEvent,
-        SyncOutputEvent,
+        AbsAxisOutputEvent, AdjustedOutputEvent, CombinedAxisOutputEvent, DetentAxisOutputEvent,
+        EncoderOutputEvent, ExpressionAxisOutputEvent, FilteredAbsAxisOutputEvent,
+        KeyComboOutputEvent, KeyOutputEvent, LayerOutputEvent, OutputDevice, OutputEvent,
+        RampAxisOutputEvent, RelAxisOutputEvent, SectorKeysOutputEvent, SmoothedAxisOutputEvent,
+        SplitAxisOutputEvent, StickAxisOutputEvent, StickMouseOutputEvent, SyncOutputEvent,
+        TrimOutputEvent,
     },
-    util::rewrap,
+    script::ScriptOutputEvent,
+    trim_file,
+};
+use evdev::EventSummary;
+use log::warn;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
 };
-use std::{collections::HashMap, io::Error};
+
+/// Where an input goes, the output mapping and which of its inputs it is
+#[derive(Clone, Copy, Debug)]
+struct Route {
+    output: usize,
+    input: usize,
+}
+
+/// An absolute axis on a virtual device written by more than one output
+#[derive(Debug)]
+struct SharedAxis {
+    device: OutputDevice,
+    code: u16,
+    /// Range of the virtual axis, that of the first output writing it
+    axis_info: AbsInfo,
+    /// Index and range of each output writing the axis
+    writers: Vec<(usize, AbsInfo)>,
+    /// Last value from each writer scaled to -1.0..1.0
+    values: HashMap<usize, f64>,
+}
+
+impl SharedAxis {
+    /// Record a value from one of the writers and return the value for the virtual axis
+    fn set_value(
+        &mut self,
+        writer: usize,
+        value: i32,
+        policy: &SharedAxisPolicy,
+        owners: &[Option<ControllerId>],
+    ) -> i32 {
+        let Some((_, info)) = self.writers.iter().find(|(w, _)| *w == writer) else {
+            return value;
+        };
+        self.values
+            .insert(writer, info.clone_set_value(value).normalised());
+
+        let written = self
+            .writers
+            .iter()
+            .filter_map(|(w, info)| self.values.get(w).map(|v| (*w, *info, *v)));
+
+        let winner = match policy {
+            SharedAxisPolicy::Error | SharedAxisPolicy::LastWriter => Some(writer),
+            SharedAxisPolicy::LargestDeflection => written
+                .max_by(|(_, _, a), (_, _, b)| a.abs().total_cmp(&b.abs()))
+                .map(|(w, _, _)| w),
+            SharedAxisPolicy::Priority(_) => written
+                .filter(|(_, info, v)| {
+                    // Moved further from the centre than the axis' flat zone
+                    let range = (info.0.maximum() - info.0.minimum()).max(1) as f64;
+                    v.abs() > 2.0 * info.0.flat() as f64 / range
+                })
+                .min_by_key(|(w, _, _)| {
+                    owners[*w].as_ref().map_or(usize::MAX, |id| policy.rank(id))
+                })
+                .map(|(w, _, _)| w),
+        };
+
+        // Nothing is deflected so the latest value is as good as any
+        let winner = winner.unwrap_or(writer);
+        self.axis_info = self.axis_info.clone_set_normalised(self.values[&winner]);
+        self.axis_info.0.value()
+    }
+}
 
 pub struct EventMapping {
-    mappings: HashMap<UniqueControllerEvent, OutputEvent>,
+    outputs: Vec<OutputEvent>,
+    /// Device whose inputs drive each output
+    owners: Vec<Option<ControllerId>>,
+    /// When each output is active
+    conditions: Vec<Option<Condition>>,
+    /// Whether each output's condition held after the last input
+    active: Vec<bool>,
+    routes: HashMap<UniqueControllerEvent, Vec<Route>>,
+    /// Input axes whose values are remapped to their calibrated range
+    calibrations: HashMap<UniqueControllerEvent, AxisCalibration>,
+    /// Centre of the calibrated range of every input axis
+    centres: HashMap<UniqueControllerEvent, i32>,
+    /// Devices whose unmapped events are dropped without a warning
+    silent: HashSet<ControllerId>,
+    state: InputState,
+    /// Inputs used by conditions
+    watched: HashSet<UniqueControllerEvent>,
+    shared: Vec<SharedAxis>,
+    shared_policy: SharedAxisPolicy,
 }
 
 impl EventMapping {
-    fn make_abs_axis_mapping(
+    fn find_axis_info(
         device_info: &DeviceInfo,
         axis_type: AbsoluteAxisType,
-        axis_event: config::AbsAxisEvent,
-    ) -> Result<OutputEvent, FatalError> {
+    ) -> Result<AbsInfo, FatalError> {
         let (_, axis_info) = device_info
             .axis_info
             .iter()
@@ -31,95 +126,765 @@ impl EventMapping {
                 axis_type
             ))?;
 
+        match device_info.calibration.get(&axis_type) {
+            Some(c) => Ok(c.apply_info(*axis_info)),
+            None => Ok(*axis_info),
+        }
+    }
+
+    fn make_abs_axis_mapping(
+        device_info: &DeviceInfo,
+        axis_type: AbsoluteAxisType,
+        axis_event: config::AbsAxisEvent,
+    ) -> Result<OutputEvent, FatalError> {
+        let axis_info = &Self::find_axis_info(device_info, axis_type)?;
+
         let output_event = match axis_event {
-            config::AbsAxisEvent::AbsAxis(a) => OutputEvent::AbsAxis(AbsAxisOutputEvent {
-                axis_type: a,
-                axis_info: *axis_info,
-            }),
-            config::AbsAxisEvent::FilteredKeys(f) => OutputEvent::FilteredAbsAxis(
-                FilteredAbsAxisOutputEvent::new(axis_type, *axis_info, f),
+            config::AbsAxisEvent::AbsAxis(a) => {
+                OutputEvent::AbsAxis(AbsAxisOutputEvent::new(a, *axis_info))
+            }
+            config::AbsAxisEvent::Scaled(s) => OutputEvent::AbsAxis(
+                AbsAxisOutputEvent::new(s.axis, *axis_info).with_range(s.range()),
             ),
+            config::AbsAxisEvent::FilteredKeys(f) => {
+                OutputEvent::FilteredAbsAxis(FilteredAbsAxisOutputEvent::new(*axis_info, f))
+            }
+            config::AbsAxisEvent::Split {
+                negative,
+                positive,
+                min,
+                max,
+            } => OutputEvent::SplitAxis(SplitAxisOutputEvent::new(
+                [negative, positive],
+                *axis_info,
+                OutputRange::output_info(min, max, *axis_info),
+            )),
+            config::AbsAxisEvent::Encoder(e) => {
+                OutputEvent::Encoder(EncoderOutputEvent::new(e, Some(*axis_info)))
+            }
+            config::AbsAxisEvent::Detents {
+                axis,
+                detents,
+                min,
+                max,
+            } => OutputEvent::DetentAxis(DetentAxisOutputEvent::new(
+                axis,
+                *axis_info,
+                OutputRange::output_info(min, max, *axis_info),
+                detents,
+            )),
+            config::AbsAxisEvent::Smoothed(s) => {
+                if let Some(filter) = &s.filter {
+                    Self::check_filter(filter)?;
+                }
+                let output_info = OutputRange::output_info(s.min, s.max, *axis_info);
+                OutputEvent::SmoothedAxis(SmoothedAxisOutputEvent::new(s, *axis_info, output_info))
+            }
+        };
+
+        Ok(output_event)
+    }
+
+    /// A filter that can't reach its input would keep updating forever
+    fn check_filter(filter: &config::AxisFilter) -> Result<(), FatalError> {
+        // Comparisons are written so NaN fails them
+        let (valid, needs) = match *filter {
+            config::AxisFilter::MovingAverage { samples } => (samples > 0, "at least 1 sample"),
+            config::AxisFilter::Exponential { alpha } => {
+                (alpha > 0.0 && alpha <= 1.0, "an alpha above 0 and up to 1")
+            }
+            config::AxisFilter::OneEuro {
+                min_cutoff,
+                beta,
+                d_cutoff,
+            } => (
+                min_cutoff > 0.0 && beta >= 0.0 && d_cutoff > 0.0,
+                "cutoffs above 0 and a beta of at least 0",
+            ),
+        };
+
+        match valid {
+            true => Ok(()),
+            false => Err(format!("Filter {:?} needs {}", filter, needs))?,
+        }
+    }
+
+    fn make_axis_pair_mapping(
+        device_info: &DeviceInfo,
+        input: AxisPair,
+        pair_event: config::AxisPairEvent,
+    ) -> Result<OutputEvent, FatalError> {
+        let axis_info = [
+            Self::find_axis_info(device_info, input.x)?,
+            Self::find_axis_info(device_info, input.y)?,
+        ];
+
+        let output_event = match pair_event {
+            config::AxisPairEvent::Dpad {
+                deadzone,
+                dpad,
+                eight_way,
+            } => OutputEvent::SectorKeys(SectorKeysOutputEvent::new(
+                axis_info,
+                deadzone,
+                dpad.sectors(eight_way),
+            )),
+            config::AxisPairEvent::Sectors { deadzone, sectors } => {
+                let sectors = sectors.into_iter().map(|k| vec![k]).collect();
+                OutputEvent::SectorKeys(SectorKeysOutputEvent::new(axis_info, deadzone, sectors))
+            }
+            config::AxisPairEvent::StickAxis {
+                axes,
+                min,
+                max,
+                shape,
+            } => OutputEvent::StickAxis(StickAxisOutputEvent::new(
+                [axes.x, axes.y],
+                axis_info,
+                axis_info.map(|i| OutputRange::output_info(min, max, i)),
+                shape,
+            )),
+            config::AxisPairEvent::Mouse { mouse } => {
+                OutputEvent::StickMouse(StickMouseOutputEvent::new(axis_info, mouse))
+            }
         };
 
         Ok(output_event)
     }
 
+    fn make_combined_axis_mapping(
+        id_and_info: &HashMap<ControllerId, DeviceInfo>,
+        combined: config::CombinedAxisConfig,
+    ) -> Result<OutputEvent, FatalError> {
+        let find_input_info = |input: &config::DeviceAxis| {
+            Self::find_axis_info(&id_and_info[&input.id()], input.axis())
+        };
+
+        let inputs = [
+            find_input_info(&combined.inputs[0])?,
+            find_input_info(&combined.inputs[1])?,
+        ];
+
+        Ok(OutputEvent::CombinedAxis(
+            CombinedAxisOutputEvent::new(combined.output, inputs, combined.op)
+                .with_range(combined.min, combined.max),
+        ))
+    }
+
+    fn make_expression_axis_mapping(
+        id_and_info: &HashMap<ControllerId, DeviceInfo>,
+        config: config::ExpressionAxisConfig,
+    ) -> Result<OutputEvent, FatalError> {
+        let inputs = config
+            .inputs
+            .iter()
+            .map(|i| {
+                let info = Self::find_axis_info(&id_and_info[&i.axis.id()], i.axis.axis())?;
+                Ok((info, i.range))
+            })
+            .collect::<Result<_, FatalError>>()?;
+
+        Ok(OutputEvent::ExpressionAxis(ExpressionAxisOutputEvent::new(
+            config.output,
+            inputs,
+            config.expression,
+            config.range,
+            config.output_range,
+        )))
+    }
+
+    /// Trims offset axes written by other mappings so those must already have been added
+    fn make_trim_mapping(&self, config: config::TrimConfig) -> Result<OutputEvent, FatalError> {
+        // Also rejects NaN, which can't be clamped to
+        if config.step.is_nan() || config.step <= 0.0 {
+            Err(format!(
+                "Trim step of {:?} must be more than 0",
+                config.trim
+            ))?;
+        }
+        if config.limit.is_nan() || config.limit < 0.0 {
+            Err(format!(
+                "Trim limit of {:?} must be at least 0",
+                config.trim
+            ))?;
+        }
+
+        let (_, axis_info) = self
+            .outputs
+            .iter()
+            .flat_map(|o| o.abs_axes())
+            .find(|(a, _)| *a == config.trim)
+            .ok_or(format!(
+                "Trimmed axis {:?} is not the output of any mapping",
+                config.trim
+            ))?;
+
+        let offset = match &config.file {
+            Some(path) => trim_file::load(path)?
+                .get(&config.trim)
+                .copied()
+                .unwrap_or_default(),
+            None => 0.0,
+        };
+
+        Ok(OutputEvent::Trim(TrimOutputEvent::new(
+            config, axis_info, offset,
+        )))
+    }
+
     fn make_mapping(
+        &self,
         mapping: config::EventMapping,
-        device_info: &DeviceInfo,
+        id: &ControllerId,
+        id_and_info: &HashMap<ControllerId, DeviceInfo>,
     ) -> Result<OutputEvent, FatalError> {
+        let device_info = &id_and_info[id];
         let output = match mapping {
-            config::EventMapping::KeyEvent { input: _, output } => {
-                OutputEvent::Key(KeyOutputEvent::new(output, 0))
+            config::EventMapping::KeyEvent { input: _, output } => OutputEvent::Key(
+                KeyOutputEvent::new(output, 0).on_device(OutputDevice::for_key(output)),
+            ),
+            config::EventMapping::KeyCombo { input: _, output } => {
+                OutputEvent::KeyCombo(KeyComboOutputEvent::new(output.combo))
+            }
+            config::EventMapping::Layer { input: _, output } => {
+                OutputEvent::Layer(LayerOutputEvent::new(output))
+            }
+            config::EventMapping::Script { input, output } => {
+                let inputs = input.into_iter().map(ControllerInputEvent::from).collect();
+                OutputEvent::Script(ScriptOutputEvent::new(output, inputs)?)
             }
             config::EventMapping::AbsAxisEvent { input, output } => {
                 Self::make_abs_axis_mapping(device_info, input, output)?
             }
+            config::EventMapping::AxisPair { input, output } => {
+                Self::make_axis_pair_mapping(device_info, input, output)?
+            }
+            config::EventMapping::RelAxisEvent { input: _, output } => {
+                OutputEvent::Encoder(EncoderOutputEvent::new(output, None))
+            }
+            config::EventMapping::ButtonAxis { input: _, output } => {
+                OutputEvent::RampAxis(RampAxisOutputEvent::new(output))
+            }
+            config::EventMapping::ButtonTrim { input: _, output } => {
+                self.make_trim_mapping(output)?
+            }
+            config::EventMapping::CombinedAxis(c) => {
+                Self::make_combined_axis_mapping(id_and_info, c)?
+            }
+            config::EventMapping::ExpressionAxis(e) => {
+                Self::make_expression_axis_mapping(id_and_info, e)?
+            }
         };
 
         Ok(output)
     }
 
-    fn make_sync_mapping(id: ControllerId) -> (UniqueControllerEvent, OutputEvent) {
+    fn make_sync_mapping(id: ControllerId) -> (Vec<UniqueControllerEvent>, OutputEvent) {
         let input = ControllerInputEvent::Synchronization(Synchronization(
             evdev::SynchronizationCode::SYN_REPORT,
         ));
         let output = OutputEvent::Synchronization(SyncOutputEvent::new());
-        (UniqueControllerEvent::new(id, input), output)
+        (vec![UniqueControllerEvent::new(id, input)], output)
+    }
+
+    fn add(
+        &mut self,
+        inputs: Vec<UniqueControllerEvent>,
+        output: OutputEvent,
+        when: Option<Condition>,
+    ) {
+        let route_output = self.outputs.len();
+        self.outputs.push(output);
+        self.owners.push(inputs.first().map(|i| i.id.clone()));
+        self.active
+            .push(when.as_ref().is_none_or(|c| self.state.check(c)));
+        self.conditions.push(when);
+
+        for (input, ue) in inputs.into_iter().enumerate() {
+            let route = Route {
+                output: route_output,
+                input,
+            };
+
+            // An input can drive any number of mappings
+            self.routes.entry(ue).or_default().push(route);
+        }
+    }
+
+    /// Map each key and axis of a device not used by another mapping to itself
+    fn make_passthrough_mappings(
+        id: &ControllerId,
+        device_info: &DeviceInfo,
+        used: &HashSet<UniqueControllerEvent>,
+    ) -> Result<Vec<(Vec<UniqueControllerEvent>, OutputEvent)>, FatalError> {
+        let mut passthrough = Vec::new();
+
+        // Keyboard keys and mouse buttons go to their own devices so typing and clicking still work
+        for key in device_info.key_info.iter() {
+            let output = KeyOutputEvent::new(*key, 0).on_device(OutputDevice::for_key(*key));
+            passthrough.push((ControllerInputEvent::Key(*key), OutputEvent::Key(output)));
+        }
+
+        for axis in device_info.rel_info.iter() {
+            let output = OutputEvent::RelAxis(RelAxisOutputEvent::new(*axis));
+            passthrough.push((ControllerInputEvent::RelAxis(*axis), output));
+        }
+
+        for axis in device_info.axis_info.keys() {
+            let axis_info = Self::find_axis_info(device_info, *axis)?;
+            let output = OutputEvent::AbsAxis(AbsAxisOutputEvent::new(*axis, axis_info));
+            passthrough.push((ControllerInputEvent::AbsAxis(*axis), output));
+        }
+
+        Ok(passthrough
+            .into_iter()
+            .map(|(input, output)| (UniqueControllerEvent::new(id.clone(), input), output))
+            .filter(|(ue, _)| !used.contains(ue))
+            .map(|(ue, output)| (vec![ue], output))
+            .collect())
     }
 
     pub fn new(
-        config: ConfigMap,
-        paths_and_devs: &HashMap<ControllerId, Device>,
+        profile: &Profile,
+        id_and_info: &HashMap<ControllerId, DeviceInfo>,
     ) -> Result<Self, FatalError> {
-        let id_and_info_or_error: Result<HashMap<_, _>, Error> = paths_and_devs
+        // Trims need the ranges of the axes written by everything else
+        let (trims, others): (Vec<_>, Vec<_>) = profile
+            .mappings
+            .clone()
+            .into_iter()
+            .partition(|(_, m)| matches!(m.mapping, config::EventMapping::ButtonTrim { .. }));
+
+        let calibrations = id_and_info
+            .iter()
+            .flat_map(|(id, info)| {
+                info.calibration.iter().map(|(axis, c)| {
+                    let input = ControllerInputEvent::AbsAxis(*axis);
+                    (UniqueControllerEvent::new(id.clone(), input), *c)
+                })
+            })
+            .collect();
+
+        let centres = id_and_info
             .iter()
-            .map(|(id, d)| rewrap(id, get_device_info(d)))
+            .flat_map(|(id, info)| {
+                info.axis_info.keys().filter_map(|axis| {
+                    let centre = Self::find_axis_info(info, *axis).ok()?;
+                    let input = ControllerInputEvent::AbsAxis(*axis);
+                    Some((
+                        UniqueControllerEvent::new(id.clone(), input),
+                        centre.clone_set_normalised(0.0).0.value(),
+                    ))
+                })
+            })
+            .collect();
+
+        let silent = profile.unmapped.keys().cloned().collect();
+
+        // Inputs only used by conditions still need to be tracked
+        let watched = profile
+            .mappings
+            .iter()
+            .filter_map(|(_, m)| m.when.as_ref())
+            .flat_map(|c| c.inputs())
+            .collect();
+
+        let mut mappings = EventMapping {
+            outputs: Vec::new(),
+            owners: Vec::new(),
+            conditions: Vec::new(),
+            active: Vec::new(),
+            routes: HashMap::new(),
+            calibrations,
+            centres,
+            silent,
+            state: InputState::new(id_and_info),
+            watched,
+            shared: Vec::new(),
+            shared_policy: profile.shared_axes.clone(),
+        };
+
+        for (ue, m) in others {
+            let inputs = m.mapping.inputs(&ue.id);
+            let output = mappings.make_mapping(m.mapping, &ue.id, id_and_info)?;
+            mappings.add(inputs, output, m.when);
+        }
+
+        let used: HashSet<_> = mappings
+            .routes
+            .keys()
+            .chain(mappings.watched.iter())
+            .cloned()
+            .chain(trims.iter().flat_map(|(ue, m)| m.mapping.inputs(&ue.id)))
             .collect();
 
-        let id_and_info = id_and_info_or_error?;
+        for (id, info) in id_and_info.iter() {
+            if profile.unmapped_policy(id) == UnmappedPolicy::Passthrough {
+                for (inputs, output) in Self::make_passthrough_mappings(id, info, &used)? {
+                    mappings.add(inputs, output, None);
+                }
+            }
+        }
 
-        let input_mappings_or_error: Result<HashMap<_, _>, FatalError> = config
+        for (ue, m) in trims {
+            let inputs = m.mapping.inputs(&ue.id);
+            let output = mappings.make_mapping(m.mapping, &ue.id, id_and_info)?;
+            mappings.add(inputs, output, m.when);
+        }
+
+        for id in id_and_info.keys() {
+            let (inputs, output) = Self::make_sync_mapping(id.clone());
+            mappings.add(inputs, output, None);
+        }
+
+        mappings.shared = mappings.find_shared_axes()?;
+
+        Ok(mappings)
+    }
+
+    /// Axes written by more than one output, which must be allowed by the shared axis policy
+    fn find_shared_axes(&self) -> Result<Vec<SharedAxis>, FatalError> {
+        let mut shared: Vec<SharedAxis> = Vec::new();
+        for (index, output) in self.outputs.iter().enumerate() {
+            let device = output.device();
+            for (axis, info) in output.abs_axes() {
+                match shared
+                    .iter_mut()
+                    .find(|s| s.device == device && s.code == axis.0 .0)
+                {
+                    Some(s) => s.writers.push((index, info)),
+                    None => shared.push(SharedAxis {
+                        device,
+                        code: axis.0 .0,
+                        axis_info: info,
+                        writers: vec![(index, info)],
+                        values: HashMap::new(),
+                    }),
+                }
+            }
+        }
+
+        shared.retain(|s| s.writers.len() > 1);
+
+        if let (SharedAxisPolicy::Error, Some(s)) = (&self.shared_policy, shared.first()) {
+            Err(format!(
+                "{:?} on the virtual {:?} is written by {} mappings, set shared_axes to choose how they are combined",
+                evdev::AbsoluteAxisCode(s.code),
+                s.device,
+                s.writers.len()
+            ))?;
+        }
+
+        Ok(shared)
+    }
+
+    /// Replace the values `event` writes to shared axes with the values chosen by the policy
+    fn resolve_shared_axes(&mut self, writer: usize, event: OutputEvent) -> OutputEvent {
+        if self.shared.is_empty() {
+            return event;
+        }
+
+        let device = event.device();
+        let mut resolved = false;
+        let events = event
+            .to_evdev_events()
             .into_iter()
-            .map(|(ue, m)| {
-                let info = &id_and_info[&ue.id];
-                rewrap(ue, Self::make_mapping(m, info))
+            .map(|e| {
+                let EventSummary::AbsoluteAxis(_, code, value) = e.kind() else {
+                    return e;
+                };
+
+                let shared = self
+                    .shared
+                    .iter_mut()
+                    .find(|s| s.device == device && s.code == code.0);
+
+                match shared {
+                    Some(s) => {
+                        resolved = true;
+                        let value = s.set_value(writer, value, &self.shared_policy, &self.owners);
+                        InputEvent::new(evdev::EventType::ABSOLUTE, code.0, value)
+                    }
+                    None => e,
+                }
             })
             .collect();
 
-        let input_mappings = input_mappings_or_error?;
+        match resolved {
+            true => OutputEvent::Adjusted(AdjustedOutputEvent::new(device, events)),
+            false => event,
+        }
+    }
 
-        let builtins: HashMap<_, _> = paths_and_devs
+    /// Offset the axes of `event` that are trimmed, remembering their untrimmed values
+    fn apply_trims(&mut self, event: OutputEvent) -> OutputEvent {
+        let has_trims = self
+            .outputs
             .iter()
-            .map(|(i, _)| Self::make_sync_mapping(i.clone()))
-            .collect();
+            .any(|o| matches!(o, OutputEvent::Trim(_)));
+
+        // A trim's own output is already trimmed
+        if !has_trims || matches!(event, OutputEvent::Trim(_)) {
+            return event;
+        }
+
+        let mut trimmed = false;
+        let events = event
+            .to_evdev_events()
+            .into_iter()
+            .map(|e| {
+                let EventSummary::AbsoluteAxis(_, code, value) = e.kind() else {
+                    return e;
+                };
+
+                let trim = self.outputs.iter_mut().find_map(|o| match o {
+                    OutputEvent::Trim(t) if t.config().trim.0 == code => Some(t),
+                    _ => None,
+                });
 
-        let mappings: HashMap<_, _> = input_mappings.into_iter().chain(builtins).collect();
+                match trim {
+                    Some(t) => {
+                        trimmed = true;
+                        t.apply(value)
+                    }
+                    None => e,
+                }
+            })
+            .collect();
 
-        Ok(EventMapping { mappings })
+        match trimmed {
+            true => OutputEvent::Adjusted(AdjustedOutputEvent::new(event.device(), events)),
+            false => event,
+        }
     }
 
+    /// Update every mapping the input drives and return their output
     pub fn get_output_event(
-        &self,
+        &mut self,
         id: ControllerId,
         input_event: InputEvent,
-    ) -> Result<OutputEvent, NonFatalError> {
+        now: Instant,
+    ) -> Result<Vec<OutputEvent>, NonFatalError> {
         let value = input_event.0.value();
-        let event: ControllerInputEvent = input_event.try_into()?;
+        let event = match ControllerInputEvent::try_from(input_event) {
+            Ok(event) => event,
+            Err(_) if self.silent.contains(&id) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
         let ue = UniqueControllerEvent::new(id, event);
 
-        let output_event = self.mappings.get(&ue);
+        // Conditions use the values reported by the device
+        self.state.set_value(ue.clone(), value);
 
-        match output_event {
-            Some(ev) => Ok(ev.clone_set_value(value)),
+        let value = self.calibrate(&ue, value);
+
+        let routes = match self.routes.get(&ue) {
+            Some(routes) => routes.clone(),
+            // Only used by conditions, which may have changed
+            None if self.watched.contains(&ue) => Vec::new(),
+            None if self.silent.contains(&ue.id) => return Ok(vec![]),
             None => Err(NonFatalError::from(format!(
                 "No mapping for event type {:?}",
                 ue.event
-            ))),
+            )))?,
+        };
+
+        // Always pass on releases so a button doesn't stick when its condition stops holding
+        let release = matches!(ue.event, ControllerInputEvent::Key(_)) && value == 0;
+
+        let mut events = Vec::new();
+        for r in routes {
+            let active = match &self.conditions[r.output] {
+                Some(c) => self.state.check(c),
+                None => true,
+            };
+
+            if !active && !release {
+                continue;
+            }
+
+            let ev = &mut self.outputs[r.output];
+            match ev {
+                OutputEvent::Script(s) => {
+                    // Don't hold up the other mappings
+                    if let Err(e) = s.set_value(r.input, value, &self.state, now) {
+                        warn!("{}", e);
+                        continue;
+                    }
+                }
+                _ => ev.set_value(r.input, value, now),
+            }
+
+            if let OutputEvent::Layer(l) = ev {
+                self.state.set_layer(l.layer(), l.active());
+            }
+
+            if let OutputEvent::Trim(t) = ev {
+                if let (true, Some(path)) = (t.changed(), &t.config().file) {
+                    if let Err(e) = trim_file::save(path, t.config().trim, t.offset()) {
+                        warn!("Failed to save trim to {:?}. {}", path, e);
+                    }
+                }
+            }
+
+            let ev = ev.snapshot();
+            let ev = self.resolve_shared_axes(r.output, ev);
+            events.push(self.apply_trims(ev));
+        }
+
+        events.extend(self.update_active(now));
+
+        Ok(events)
+    }
+
+    fn calibrate(&self, input: &UniqueControllerEvent, value: i32) -> i32 {
+        match self.calibrations.get(input) {
+            Some(c) => c.apply(value),
+            None => value,
+        }
+    }
+
+    /// Centre the input axes of outputs whose condition stopped holding and move them to
+    /// the current input values when it holds again, so they don't stay where they were
+    fn update_active(&mut self, now: Instant) -> Vec<OutputEvent> {
+        let mut events = Vec::new();
+        for output in 0..self.outputs.len() {
+            let Some(condition) = &self.conditions[output] else {
+                continue;
+            };
+
+            let active = self.state.check(condition);
+            if active == std::mem::replace(&mut self.active[output], active) {
+                continue;
+            }
+
+            events.extend(self.sync_axes(output, active, now));
+        }
+
+        events
+    }
+
+    /// Set the input axes of an output to their current values, or their centres while
+    /// it is inactive, returning its output if it has any
+    fn sync_axes(&mut self, output: usize, active: bool, now: Instant) -> Option<OutputEvent> {
+        // Scripts are given every input through the state instead and encoders would tap
+        // their way to the centre
+        if matches!(
+            self.outputs[output],
+            OutputEvent::Script(_) | OutputEvent::Encoder(_)
+        ) {
+            return None;
+        }
+
+        let axes: Vec<(usize, i32)> = self
+            .routes
+            .iter()
+            .flat_map(|(ue, routes)| routes.iter().map(move |r| (ue, r)))
+            .filter(|(_, r)| r.output == output)
+            .filter_map(|(ue, r)| {
+                let centre = *self.centres.get(ue)?;
+                let value = match active {
+                    true => self.calibrate(ue, self.state.value(ue)),
+                    false => centre,
+                };
+                Some((r.input, value))
+            })
+            .collect();
+
+        if axes.is_empty() {
+            return None;
+        }
+
+        let ev = &mut self.outputs[output];
+        for (input, value) in axes {
+            ev.set_value(input, value, now);
+        }
+
+        let ev = ev.snapshot();
+        let ev = self.resolve_shared_axes(output, ev);
+        Some(self.apply_trims(ev))
+    }
+
+    /// Start from the current input values, e.g. those left by the previous profile, instead
+    /// of those when the devices were opened and return the events putting every output axis there
+    pub fn resume(&mut self, state: &InputState, now: Instant) -> Vec<OutputEvent> {
+        self.state = state.without_layers();
+
+        let mut events = Vec::new();
+        for output in 0..self.outputs.len() {
+            let active = self.conditions[output]
+                .as_ref()
+                .is_none_or(|c| self.state.check(c));
+            self.active[output] = active;
+
+            if let Some(ev) = self.sync_axes(output, active, now) {
+                events.push(ev);
+                continue;
+            }
+
+            // Axes driven by buttons are written where they start
+            let ev = &self.outputs[output];
+            let axes: Vec<InputEvent> = ev
+                .abs_axes()
+                .into_iter()
+                .map(|(axis, info)| {
+                    InputEvent::new(evdev::EventType::ABSOLUTE, axis.0 .0, info.0.value())
+                })
+                .collect();
+            if !axes.is_empty() {
+                let ev = OutputEvent::Adjusted(AdjustedOutputEvent::new(ev.device(), axes));
+                let ev = self.resolve_shared_axes(output, ev);
+                events.push(self.apply_trims(ev));
+            }
         }
+
+        events
+    }
+
+    pub fn state(&self) -> &InputState {
+        &self.state
+    }
+
+    /// The earliest time any mapping needs updating without new input
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.outputs
+            .iter()
+            .filter_map(|ev| ev.next_deadline())
+            .min()
+    }
+
+    /// Update the mappings whose deadline has passed and return their output
+    pub fn get_timed_events(&mut self, now: Instant) -> Vec<OutputEvent> {
+        let events: Vec<(usize, OutputEvent)> = self
+            .outputs
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, ev)| ev.next_deadline().is_some_and(|d| d <= now))
+            .filter_map(|(index, ev)| {
+                match ev {
+                    OutputEvent::Script(s) => {
+                        // Don't hold up the other mappings
+                        if let Err(e) = s.tick(now, &self.state) {
+                            warn!("{}", e);
+                            return None;
+                        }
+                    }
+                    _ => ev.tick(now),
+                }
+                Some((index, ev.snapshot()))
+            })
+            .collect();
+
+        events
+            .into_iter()
+            .map(|(index, e)| {
+                let e = self.resolve_shared_axes(index, e);
+                self.apply_trims(e)
+            })
+            .collect()
     }
 
     pub fn list_output_events(&self) -> Vec<&OutputEvent> {
-        self.mappings.values().collect()
+        self.outputs.iter().collect()
     }
 }
@@ -1,22 +1,52 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    f64::consts::TAU,
+    time::Instant,
+};
+
 use crate::{
-    config::FilteredKeyMapping,
-    ew_types::{AbsInfo, AbsoluteAxisType, InputEvent, KeyCode},
+    config::{
+        AxisFilter, CombineOp, Detent, EncoderMapping, FilteredKeyMapping, LayerConfig,
+        MouseConfig, OutputRange, RampAxis, SmoothedAxis, StickShape, TrimConfig, ValueRange,
+    },
+    ew_types::{AbsInfo, AbsoluteAxisType, InputEvent, KeyCode, RelativeAxisType},
+    expression::Expression,
+    script::ScriptOutputEvent,
 };
 
+/// An input axis written to an output axis, rescaled through -1.0..1.0 if the ranges differ
 #[derive(Clone, Debug)]
 pub struct AbsAxisOutputEvent {
     pub axis_type: AbsoluteAxisType,
     pub axis_info: AbsInfo,
+    input_info: AbsInfo,
 }
 
 impl AbsAxisOutputEvent {
-    pub fn clone_set_value(&self, value: i32) -> Self {
+    /// The output has the same range as the input
+    pub fn new(axis_type: AbsoluteAxisType, input_info: AbsInfo) -> Self {
         AbsAxisOutputEvent {
-            axis_type: self.axis_type,
-            axis_info: self.axis_info.clone_set_value(value),
+            axis_type,
+            axis_info: input_info,
+            input_info,
         }
     }
 
+    /// The output has its own range
+    pub fn with_range(self, range: OutputRange) -> Self {
+        let axis_info = range
+            .axis_info()
+            .clone_set_normalised(self.input_info.normalised());
+        AbsAxisOutputEvent { axis_info, ..self }
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        self.input_info = self.input_info.clone_set_value(value);
+        self.axis_info = self
+            .axis_info
+            .clone_set_normalised(self.input_info.normalised());
+    }
+
     pub fn to_evdev_event(&self) -> InputEvent {
         InputEvent::new(
             evdev::EventType::ABSOLUTE,
@@ -30,11 +60,21 @@ impl AbsAxisOutputEvent {
 pub struct KeyOutputEvent {
     code: KeyCode,
     value: i32,
+    device: OutputDevice,
 }
 
 impl KeyOutputEvent {
     pub fn new(code: KeyCode, value: i32) -> Self {
-        KeyOutputEvent { code, value }
+        KeyOutputEvent {
+            code,
+            value,
+            device: OutputDevice::Gamepad,
+        }
+    }
+
+    /// The same key written to `device` instead of the gamepad
+    pub fn on_device(self, device: OutputDevice) -> Self {
+        KeyOutputEvent { device, ..self }
     }
 
     pub fn code(&self) -> KeyCode {
@@ -45,6 +85,10 @@ impl KeyOutputEvent {
         self.value
     }
 
+    pub fn set_value(&mut self, value: i32) {
+        self.value = value;
+    }
+
     pub fn to_evdev_event(&self) -> InputEvent {
         InputEvent::new(evdev::EventType::KEY, self.code().0 .0, self.value())
     }
@@ -52,7 +96,35 @@ impl KeyOutputEvent {
 
 impl From<KeyCode> for KeyOutputEvent {
     fn from(k: KeyCode) -> Self {
-        KeyOutputEvent { code: k, value: 0 }
+        KeyOutputEvent::new(k, 0)
+    }
+}
+
+/// A relative axis written unchanged
+#[derive(Clone, Debug)]
+pub struct RelAxisOutputEvent {
+    axis_type: RelativeAxisType,
+    value: i32,
+}
+
+impl RelAxisOutputEvent {
+    pub fn new(axis_type: RelativeAxisType) -> Self {
+        RelAxisOutputEvent {
+            axis_type,
+            value: 0,
+        }
+    }
+
+    pub fn axis_type(&self) -> RelativeAxisType {
+        self.axis_type
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        self.value = value;
+    }
+
+    pub fn to_evdev_event(&self) -> InputEvent {
+        InputEvent::new(evdev::EventType::RELATIVE, self.axis_type.0 .0, self.value)
     }
 }
 
@@ -67,8 +139,8 @@ impl SyncOutputEvent {
         Self { code: 0, value: 0 }
     }
 
-    pub fn clone_set_value(&self, value: i32) -> Self {
-        Self { code: 0, value }
+    pub fn set_value(&mut self, value: i32) {
+        self.value = value;
     }
 
     pub fn code(&self) -> u16 {
@@ -84,74 +156,1347 @@ impl SyncOutputEvent {
     }
 }
 
+/// Remembers what was last written for a set of output keys so only changes are emitted
+#[derive(Clone, Debug, Default)]
+struct KeyStates {
+    emitted: BTreeMap<u16, bool>,
+    /// Keys that changed in the last update
+    changed: Vec<(u16, bool)>,
+}
+
+impl KeyStates {
+    fn update(&mut self, pressed: BTreeMap<u16, bool>) {
+        self.changed = pressed
+            .into_iter()
+            .filter(|(code, p)| self.emitted.get(code).copied().unwrap_or_default() != *p)
+            .collect();
+        self.emitted.extend(self.changed.iter().copied());
+    }
+
+    fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.changed
+            .iter()
+            .map(|(code, pressed)| InputEvent::new(evdev::EventType::KEY, *code, *pressed as i32))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ZoneState {
+    pressed: bool,
+    /// When the axis value started asking for the key to change
+    changing_since: Option<Instant>,
+}
+
+impl ZoneState {
+    fn update(&mut self, mapping: &FilteredKeyMapping, value: i32, now: Instant) {
+        // Widen the zone while pressed so noise around min / max doesn't chatter
+        let margin = if self.pressed { mapping.hysteresis } else { 0 };
+        let in_zone = value >= mapping.min - margin && value <= mapping.max + margin;
+
+        if in_zone == self.pressed {
+            self.changing_since = None;
+            return;
+        }
+
+        let since = *self.changing_since.get_or_insert(now);
+        if now.duration_since(since) >= mapping.hold() {
+            self.pressed = in_zone;
+            self.changing_since = None;
+        }
+    }
+
+    fn deadline(&self, mapping: &FilteredKeyMapping) -> Option<Instant> {
+        self.changing_since.map(|since| since + mapping.hold())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FilteredAbsAxisOutputEvent {
-    axis_type: AbsoluteAxisType,
     axis_info: AbsInfo,
     mappings: Vec<FilteredKeyMapping>,
+    zones: Vec<ZoneState>,
+    keys: KeyStates,
 }
 
 impl FilteredAbsAxisOutputEvent {
-    pub fn new(
-        input_axis_type: AbsoluteAxisType,
-        info: AbsInfo,
-        mappings: Vec<FilteredKeyMapping>,
-    ) -> Self {
+    pub fn new(info: AbsInfo, mappings: Vec<FilteredKeyMapping>) -> Self {
+        let zones = vec![ZoneState::default(); mappings.len()];
         FilteredAbsAxisOutputEvent {
-            axis_type: input_axis_type,
             axis_info: info,
             mappings,
+            zones,
+            keys: KeyStates::default(),
         }
     }
     pub fn codes(&self) -> Vec<KeyCode> {
         self.mappings.iter().map(|f| f.key).collect()
     }
 
-    pub fn clone_set_value(&self, value: i32) -> Self {
-        FilteredAbsAxisOutputEvent {
-            axis_type: self.axis_type,
-            axis_info: self.axis_info.clone_set_value(value),
-            mappings: self.mappings.clone(),
+    pub fn set_value(&mut self, value: i32, now: Instant) {
+        self.axis_info = self.axis_info.clone_set_value(value);
+        self.tick(now);
+    }
+
+    /// Re-evaluate zones waiting out their hold time
+    pub fn tick(&mut self, now: Instant) {
+        let value = self.axis_info.0.value();
+        let mut pressed: BTreeMap<u16, bool> = BTreeMap::new();
+        for (zone, mapping) in self.zones.iter_mut().zip(self.mappings.iter()) {
+            zone.update(mapping, value, now);
+
+            // A key is pressed if any of its zones are
+            *pressed.entry(mapping.key.0 .0).or_default() |= zone.pressed;
+        }
+
+        self.keys.update(pressed);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.zones
+            .iter()
+            .zip(self.mappings.iter())
+            .filter_map(|(zone, mapping)| zone.deadline(mapping))
+            .min()
+    }
+
+    /// Only the keys that changed state in the last update
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.keys.to_evdev_events()
+    }
+}
+
+/// Presses keys depending on which direction a stick is pushed
+#[derive(Clone, Debug)]
+pub struct SectorKeysOutputEvent {
+    axis_info: [AbsInfo; 2],
+    deadzone: f64,
+    /// Keys pressed in each equally sized sector, clockwise starting from up
+    sectors: Vec<Vec<KeyCode>>,
+    keys: KeyStates,
+}
+
+impl SectorKeysOutputEvent {
+    pub fn new(axis_info: [AbsInfo; 2], deadzone: f64, sectors: Vec<Vec<KeyCode>>) -> Self {
+        SectorKeysOutputEvent {
+            axis_info,
+            deadzone,
+            sectors,
+            keys: KeyStates::default(),
+        }
+    }
+
+    pub fn codes(&self) -> Vec<KeyCode> {
+        self.sectors.iter().flatten().copied().collect()
+    }
+
+    fn active_sector(&self) -> Option<usize> {
+        let x = self.axis_info[0].normalised();
+        let y = self.axis_info[1].normalised();
+        if self.sectors.is_empty() || x.hypot(y) < self.deadzone {
+            return None;
+        }
+
+        // Clockwise from up, remembering that positive y is down
+        let angle = x.atan2(-y).rem_euclid(TAU);
+        let width = TAU / self.sectors.len() as f64;
+        let sector = ((angle + width / 2.0) / width) as usize % self.sectors.len();
+        Some(sector)
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32) {
+        self.axis_info[input] = self.axis_info[input].clone_set_value(value);
+
+        let mut pressed: BTreeMap<u16, bool> =
+            self.codes().into_iter().map(|k| (k.0 .0, false)).collect();
+        if let Some(sector) = self.active_sector() {
+            for key in self.sectors[sector].iter() {
+                pressed.insert(key.0 .0, true);
+            }
+        }
+
+        self.keys.update(pressed);
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.keys.to_evdev_events()
+    }
+}
+
+/// Reshapes the response of a stick, writing both output axes together
+#[derive(Clone, Debug)]
+pub struct StickAxisOutputEvent {
+    axis_types: [AbsoluteAxisType; 2],
+    input_info: [AbsInfo; 2],
+    axis_info: [AbsInfo; 2],
+    shape: StickShape,
+}
+
+impl StickAxisOutputEvent {
+    pub fn new(
+        axis_types: [AbsoluteAxisType; 2],
+        input_info: [AbsInfo; 2],
+        axis_info: [AbsInfo; 2],
+        shape: StickShape,
+    ) -> Self {
+        StickAxisOutputEvent {
+            axis_types,
+            input_info,
+            axis_info,
+            shape,
+        }
+    }
+
+    pub fn axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        self.axis_types.into_iter().zip(self.axis_info).collect()
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32) {
+        self.input_info[input] = self.input_info[input].clone_set_value(value);
+    }
+
+    /// Stick position after applying the deadzones, saturation and square correction
+    fn shaped(&self) -> (f64, f64) {
+        let x = self.input_info[0].normalised();
+        let y = self.input_info[1].normalised();
+        let magnitude = x.hypot(y);
+        let shape = &self.shape;
+
+        if magnitude <= shape.deadzone || magnitude == 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let live_range = (shape.saturation - shape.deadzone).max(f64::EPSILON);
+        let scaled = ((magnitude - shape.deadzone) / live_range).min(1.0);
+        let mut new_magnitude = shape.anti_deadzone + (1.0 - shape.anti_deadzone) * scaled;
+
+        // Stretch the circle out to the corners of the square
+        if shape.square {
+            new_magnitude *= magnitude / x.abs().max(y.abs());
+        }
+
+        let factor = new_magnitude / magnitude;
+        ((x * factor).clamp(-1.0, 1.0), (y * factor).clamp(-1.0, 1.0))
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        let (x, y) = self.shaped();
+        self.axis_types
+            .iter()
+            .zip(self.axis_info.iter())
+            .zip([x, y])
+            .map(|((axis_type, info), value)| {
+                let info = info.clone_set_normalised(value);
+                InputEvent::new(evdev::EventType::ABSOLUTE, axis_type.0 .0, info.0.value())
+            })
+            .collect()
+    }
+}
+
+/// Moves the pointer on a fixed tick for as long as a stick is deflected
+#[derive(Clone, Debug)]
+pub struct StickMouseOutputEvent {
+    axis_info: [AbsInfo; 2],
+    config: MouseConfig,
+    last_tick: Option<Instant>,
+    /// Movement smaller than a pixel carried over to the next tick
+    remainder: (f64, f64),
+    /// Whole pixels moved on the last tick
+    movement: (i32, i32),
+}
+
+impl StickMouseOutputEvent {
+    pub fn new(axis_info: [AbsInfo; 2], config: MouseConfig) -> Self {
+        StickMouseOutputEvent {
+            axis_info,
+            config,
+            last_tick: None,
+            remainder: (0.0, 0.0),
+            movement: (0, 0),
+        }
+    }
+
+    /// Stick deflection after the deadzone and response curve, or None when centred
+    fn velocity(&self) -> Option<(f64, f64)> {
+        let x = self.axis_info[0].normalised();
+        let y = self.axis_info[1].normalised();
+        let magnitude = x.hypot(y);
+        let deadzone = self.config.deadzone;
+
+        if magnitude <= deadzone || magnitude == 0.0 {
+            return None;
+        }
+
+        let scaled = ((magnitude - deadzone) / (1.0 - deadzone).max(f64::EPSILON)).min(1.0);
+        let speed = self.config.speed * scaled.powf(self.config.acceleration);
+        Some((x / magnitude * speed, y / magnitude * speed))
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32, now: Instant) {
+        self.axis_info[input] = self.axis_info[input].clone_set_value(value);
+
+        // Movement only comes from ticks
+        self.movement = (0, 0);
+
+        match self.velocity() {
+            Some(_) => {
+                self.last_tick.get_or_insert(now);
+            }
+            None => {
+                self.last_tick = None;
+                self.remainder = (0.0, 0.0);
+            }
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.last_tick.map(|t| t + self.config.tick())
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        let (Some(last_tick), Some((vx, vy))) = (self.last_tick, self.velocity()) else {
+            return;
+        };
+
+        let elapsed = now.duration_since(last_tick).as_secs_f64();
+        let x = self.remainder.0 + vx * elapsed;
+        let y = self.remainder.1 + vy * elapsed;
+
+        self.movement = (x.trunc() as i32, y.trunc() as i32);
+        self.remainder = (x.fract(), y.fract());
+        self.last_tick = Some(now);
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        let (x, y) = self.movement;
+        [
+            (evdev::RelativeAxisCode::REL_X, x),
+            (evdev::RelativeAxisCode::REL_Y, y),
+        ]
+        .into_iter()
+        .filter(|(_, value)| *value != 0)
+        .map(|(axis, value)| InputEvent::new(evdev::EventType::RELATIVE, axis.0, value))
+        .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TapState {
+    Idle,
+    /// Key held until the given time
+    Pressed(KeyCode, Instant),
+    /// Key released, next tap not before the given time
+    Released(Instant),
+}
+
+/// Taps a key for every step an encoder is turned
+#[derive(Clone, Debug)]
+pub struct EncoderOutputEvent {
+    mapping: EncoderMapping,
+    /// Last value of an absolute encoder, relative encoders report changes directly
+    axis_info: Option<AbsInfo>,
+    /// Change not yet large enough for a tap
+    partial: i32,
+    /// Taps still to send, negative for decrease
+    queued: i32,
+    state: TapState,
+    changed: Vec<(KeyCode, i32)>,
+}
+
+impl EncoderOutputEvent {
+    pub fn new(mapping: EncoderMapping, axis_info: Option<AbsInfo>) -> Self {
+        EncoderOutputEvent {
+            mapping,
+            axis_info,
+            partial: 0,
+            queued: 0,
+            state: TapState::Idle,
+            changed: Vec::new(),
+        }
+    }
+
+    pub fn codes(&self) -> Vec<KeyCode> {
+        vec![self.mapping.increase, self.mapping.decrease]
+    }
+
+    /// Change since the last value, unwrapping an absolute encoder passing min / max
+    fn delta(&mut self, value: i32) -> i32 {
+        let Some(info) = self.axis_info else {
+            return value;
+        };
+
+        self.axis_info = Some(info.clone_set_value(value));
+
+        let range = info.0.maximum() - info.0.minimum() + 1;
+        let delta = value - info.0.value();
+        if delta > range / 2 {
+            delta - range
+        } else if delta < -range / 2 {
+            delta + range
+        } else {
+            delta
+        }
+    }
+
+    pub fn set_value(&mut self, value: i32, now: Instant) {
+        let step = self.mapping.step.max(1);
+        self.partial += self.delta(value);
+        self.queued += self.partial / step;
+        self.partial %= step;
+
+        self.changed.clear();
+        if let TapState::Idle = self.state {
+            self.next_tap(now);
+        }
+    }
+
+    fn next_tap(&mut self, now: Instant) {
+        let key = match self.queued.signum() {
+            1 => self.mapping.increase,
+            -1 => self.mapping.decrease,
+            _ => {
+                self.state = TapState::Idle;
+                return;
+            }
+        };
+
+        self.queued -= self.queued.signum();
+        self.changed.push((key, 1));
+        self.state = TapState::Pressed(key, now + self.mapping.tap());
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self.state {
+            TapState::Idle => None,
+            TapState::Pressed(_, until) => Some(until),
+            TapState::Released(until) => Some(until),
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.changed.clear();
+        match self.state {
+            TapState::Pressed(key, until) if now >= until => {
+                self.changed.push((key, 0));
+                self.state = TapState::Released(now + self.mapping.tap());
+            }
+            TapState::Released(until) if now >= until => self.next_tap(now),
+            _ => (),
+        }
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.changed
+            .iter()
+            .map(|(key, value)| InputEvent::new(evdev::EventType::KEY, key.0 .0, *value))
+            .collect()
+    }
+}
+
+/// An axis moved up or down at a fixed rate while buttons are held
+#[derive(Clone, Debug)]
+pub struct RampAxisOutputEvent {
+    config: RampAxis,
+    axis_info: AbsInfo,
+    /// Position from -1.0 to 1.0
+    position: f64,
+    /// Increase and decrease buttons
+    held: [bool; 2],
+    last_tick: Option<Instant>,
+    emitted: Option<i32>,
+    changed: bool,
+}
+
+impl RampAxisOutputEvent {
+    pub fn new(config: RampAxis) -> Self {
+        let axis_info = AbsInfo(evdev::AbsInfo::new(0, config.min, config.max, 0, 0, 0))
+            .clone_set_normalised(0.0);
+        RampAxisOutputEvent {
+            config,
+            axis_info,
+            position: 0.0,
+            held: [false, false],
+            last_tick: None,
+            emitted: None,
+            changed: false,
+        }
+    }
+
+    pub fn axis_type(&self) -> AbsoluteAxisType {
+        self.config.axis
+    }
+
+    pub fn axis_info(&self) -> AbsInfo {
+        self.axis_info
+    }
+
+    fn direction(&self) -> f64 {
+        match self.held {
+            [true, false] => 1.0,
+            [false, true] => -1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Move the axis for the time since the last update
+    fn advance(&mut self, now: Instant) {
+        if let Some(last_tick) = self.last_tick {
+            let elapsed = now.duration_since(last_tick).as_secs_f64();
+            // The full range is 2.0 wide
+            let distance = self.direction() * self.config.rate * 2.0 * elapsed;
+            self.position = (self.position + distance).clamp(-1.0, 1.0);
+        }
+
+        self.last_tick = match self.direction() != 0.0 {
+            true => Some(now),
+            false => None,
+        };
+    }
+
+    fn update_output(&mut self) {
+        self.axis_info = self.axis_info.clone_set_normalised(self.position);
+        let value = self.axis_info.0.value();
+        self.changed = self.emitted != Some(value);
+        self.emitted = Some(value);
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32, now: Instant) {
+        self.advance(now);
+
+        match input {
+            0 | 1 => self.held[input] = value != 0,
+            _ if value == 1 => self.position = 0.0,
+            _ => (),
+        }
+
+        // Start timing from when a button is pressed
+        if self.direction() != 0.0 {
+            self.last_tick.get_or_insert(now);
+        } else {
+            self.last_tick = None;
+        }
+
+        self.update_output();
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.last_tick.map(|t| t + self.config.tick())
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.advance(now);
+        self.update_output();
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        match self.changed {
+            true => vec![InputEvent::new(
+                evdev::EventType::ABSOLUTE,
+                self.config.axis.0 .0,
+                self.axis_info.0.value(),
+            )],
+            false => vec![],
+        }
+    }
+}
+
+/// An axis passed through except near detents, where it snaps to the detent's position
+#[derive(Clone, Debug)]
+pub struct DetentAxisOutputEvent {
+    axis_type: AbsoluteAxisType,
+    /// Detents are positions in the range of the input
+    input_info: AbsInfo,
+    axis_info: AbsInfo,
+    detents: Vec<Detent>,
+    keys: KeyStates,
+}
+
+impl DetentAxisOutputEvent {
+    pub fn new(
+        axis_type: AbsoluteAxisType,
+        input_info: AbsInfo,
+        axis_info: AbsInfo,
+        detents: Vec<Detent>,
+    ) -> Self {
+        DetentAxisOutputEvent {
+            axis_type,
+            input_info,
+            axis_info,
+            detents,
+            keys: KeyStates::default(),
+        }
+    }
+
+    pub fn axis_type(&self) -> AbsoluteAxisType {
+        self.axis_type
+    }
+
+    pub fn axis_info(&self) -> AbsInfo {
+        self.axis_info
+    }
+
+    pub fn codes(&self) -> Vec<KeyCode> {
+        self.detents.iter().filter_map(|d| d.key).collect()
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        let detent = self.detents.iter().find(|d| d.contains(value));
+        let snapped = detent.map_or(value, |d| d.position);
+        self.input_info = self.input_info.clone_set_value(snapped);
+        self.axis_info = self
+            .axis_info
+            .clone_set_normalised(self.input_info.normalised());
+
+        let mut pressed: BTreeMap<u16, bool> = BTreeMap::new();
+        for detent in self.detents.iter() {
+            if let Some(key) = detent.key {
+                *pressed.entry(key.0 .0).or_default() |= detent.contains(value);
+            }
+        }
+
+        self.keys.update(pressed);
+    }
+
+    /// The axis followed by the keys that changed state in the last update
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        let axis = InputEvent::new(
+            evdev::EventType::ABSOLUTE,
+            self.axis_type.0 .0,
+            self.axis_info.0.value(),
+        );
+
+        std::iter::once(axis)
+            .chain(self.keys.to_evdev_events())
+            .collect()
+    }
+}
+
+/// State of an `AxisFilter`, working on values from -1.0 to 1.0
+#[derive(Clone, Debug)]
+enum FilterState {
+    MovingAverage {
+        samples: usize,
+        values: VecDeque<f64>,
+    },
+    Exponential {
+        alpha: f64,
+        value: Option<f64>,
+    },
+    OneEuro {
+        min_cutoff: f64,
+        beta: f64,
+        d_cutoff: f64,
+        value: Option<(f64, Instant)>,
+        speed: f64,
+    },
+    None,
+}
+
+impl FilterState {
+    fn new(filter: Option<AxisFilter>) -> Self {
+        match filter {
+            Some(AxisFilter::MovingAverage { samples }) => FilterState::MovingAverage {
+                samples: samples.max(1),
+                values: VecDeque::new(),
+            },
+            Some(AxisFilter::Exponential { alpha }) => FilterState::Exponential {
+                alpha: alpha.clamp(0.0, 1.0),
+                value: None,
+            },
+            Some(AxisFilter::OneEuro {
+                min_cutoff,
+                beta,
+                d_cutoff,
+            }) => FilterState::OneEuro {
+                min_cutoff,
+                beta,
+                d_cutoff,
+                value: None,
+                speed: 0.0,
+            },
+            None => FilterState::None,
+        }
+    }
+
+    /// Smoothing factor of a low pass filter with `cutoff` in Hz sampled every `dt` seconds
+    fn low_pass_alpha(cutoff: f64, dt: f64) -> f64 {
+        let tau = 1.0 / (TAU * cutoff);
+        1.0 / (1.0 + tau / dt)
+    }
+
+    fn update(&mut self, input: f64, now: Instant) -> f64 {
+        match self {
+            FilterState::MovingAverage { samples, values } => {
+                values.push_back(input);
+                while values.len() > *samples {
+                    values.pop_front();
+                }
+                values.iter().sum::<f64>() / values.len() as f64
+            }
+            FilterState::Exponential { alpha, value } => {
+                let filtered = match value {
+                    Some(v) => *v + *alpha * (input - *v),
+                    None => input,
+                };
+                *value = Some(filtered);
+                filtered
+            }
+            FilterState::OneEuro {
+                min_cutoff,
+                beta,
+                d_cutoff,
+                value,
+                speed,
+            } => {
+                let filtered = match value {
+                    Some((v, last)) => {
+                        let dt = now.duration_since(*last).as_secs_f64();
+                        // Several events at the same time
+                        if dt <= 0.0 {
+                            return *v;
+                        }
+
+                        let raw_speed = (input - *v) / dt;
+                        *speed += Self::low_pass_alpha(*d_cutoff, dt) * (raw_speed - *speed);
+
+                        let cutoff = *min_cutoff + *beta * speed.abs();
+                        *v + Self::low_pass_alpha(cutoff, dt) * (input - *v)
+                    }
+                    None => input,
+                };
+                *value = Some((filtered, now));
+                filtered
+            }
+            FilterState::None => input,
         }
     }
+}
+
+/// An axis passed through a noise filter, updated over time after the input stops
+/// until the output catches up with it
+#[derive(Clone, Debug)]
+pub struct SmoothedAxisOutputEvent {
+    config: SmoothedAxis,
+    /// Last input accepted after the fuzz
+    input: AbsInfo,
+    axis_info: AbsInfo,
+    filter: FilterState,
+    last_tick: Option<Instant>,
+    emitted: Option<i32>,
+    changed: bool,
+}
+
+impl SmoothedAxisOutputEvent {
+    pub fn new(config: SmoothedAxis, input_info: AbsInfo, axis_info: AbsInfo) -> Self {
+        let filter = FilterState::new(config.filter.clone());
+        SmoothedAxisOutputEvent {
+            config,
+            input: input_info,
+            axis_info,
+            filter,
+            last_tick: None,
+            emitted: None,
+            changed: false,
+        }
+    }
+
+    pub fn axis_type(&self) -> AbsoluteAxisType {
+        self.config.axis
+    }
+
+    pub fn axis_info(&self) -> AbsInfo {
+        self.axis_info
+    }
+
+    fn update(&mut self, now: Instant) {
+        let filtered = self.filter.update(self.input.normalised(), now);
+        self.axis_info = self.axis_info.clone_set_normalised(filtered);
+
+        let value = self.axis_info.0.value();
+        self.changed = self.emitted != Some(value);
+        self.emitted = Some(value);
+
+        // Keep updating until the filter has caught up with the input, which may be out of range
+        let target = self.axis_info.clone_set_normalised(self.input.normalised());
+        self.last_tick = match value != target.0.value() {
+            true => Some(now),
+            false => None,
+        };
+    }
+
+    pub fn set_value(&mut self, value: i32, now: Instant) {
+        if (value - self.input.0.value()).abs() >= self.config.fuzz {
+            self.input = self.input.clone_set_value(value);
+        }
+
+        self.update(now);
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.last_tick.map(|t| t + self.config.tick())
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.update(now);
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        match self.changed {
+            true => vec![InputEvent::new(
+                evdev::EventType::ABSOLUTE,
+                self.config.axis.0 .0,
+                self.axis_info.0.value(),
+            )],
+            false => vec![],
+        }
+    }
+}
 
-    fn mapping_to_evdev_event(&self, mapping: &FilteredKeyMapping) -> InputEvent {
-        let axis_value = self.axis_info.0.value();
-        let mut out_value = 0;
-        if axis_value >= mapping.min && axis_value <= mapping.max {
-            out_value = 1
+/// Splits a centred axis into two axes, each covering one half of the input
+#[derive(Clone, Debug)]
+pub struct SplitAxisOutputEvent {
+    /// Negative then positive half
+    axis_types: [AbsoluteAxisType; 2],
+    input_info: AbsInfo,
+    /// Range of both halves
+    axis_info: AbsInfo,
+}
+
+impl SplitAxisOutputEvent {
+    pub fn new(axis_types: [AbsoluteAxisType; 2], input_info: AbsInfo, axis_info: AbsInfo) -> Self {
+        SplitAxisOutputEvent {
+            axis_types,
+            input_info,
+            axis_info,
         }
+    }
 
-        InputEvent::new(evdev::EventType::KEY, mapping.key.0 .0, out_value)
+    pub fn axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        self.axis_types
+            .iter()
+            .map(|a| (*a, self.axis_info.clone_set_normalised(-1.0)))
+            .collect()
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        self.input_info = self.input_info.clone_set_value(value);
     }
 
     pub fn to_evdev_events(&self) -> Vec<InputEvent> {
-        self.mappings
+        let value = self.input_info.normalised();
+
+        // Centre is the bottom of both halves
+        let halves = [(-value).max(0.0), value.max(0.0)];
+        self.axis_types
             .iter()
-            .map(|e| self.mapping_to_evdev_event(e))
+            .zip(halves)
+            .map(|(axis_type, half)| {
+                let info = self.axis_info.clone_set_normalised(half * 2.0 - 1.0);
+                InputEvent::new(evdev::EventType::ABSOLUTE, axis_type.0 .0, info.0.value())
+            })
             .collect()
     }
 }
+
+/// Combines the last values of two input axes into one output axis
+#[derive(Clone, Debug)]
+pub struct CombinedAxisOutputEvent {
+    axis_type: AbsoluteAxisType,
+    axis_info: AbsInfo,
+    inputs: [AbsInfo; 2],
+    op: CombineOp,
+}
+
+impl CombinedAxisOutputEvent {
+    pub fn new(axis_type: AbsoluteAxisType, inputs: [AbsInfo; 2], op: CombineOp) -> Self {
+        let mut combined = CombinedAxisOutputEvent {
+            axis_type,
+            axis_info: Self::range(&inputs, op),
+            inputs,
+            op,
+        };
+        combined.update();
+        combined
+    }
+
+    /// The output has either end replaced
+    pub fn with_range(self, min: Option<i32>, max: Option<i32>) -> Self {
+        let axis_info = OutputRange::output_info(min, max, self.axis_info);
+        CombinedAxisOutputEvent { axis_info, ..self }
+    }
+
+    /// Range the inputs would cover if combined without scaling, a sum saturates at the ends
+    /// of the first input's range instead
+    fn range(inputs: &[AbsInfo; 2], op: CombineOp) -> AbsInfo {
+        let [a, b] = inputs.map(|i| i.0);
+        let combine = |a: i32, b: i32| match op {
+            CombineOp::Sum => a,
+            CombineOp::Difference => a - b,
+            CombineOp::Max => a.max(b),
+            CombineOp::Average => (a + b) / 2,
+        };
+        let (min, max) = match op {
+            CombineOp::Difference => (
+                combine(a.minimum(), b.maximum()),
+                combine(a.maximum(), b.minimum()),
+            ),
+            _ => (
+                combine(a.minimum(), b.minimum()),
+                combine(a.maximum(), b.maximum()),
+            ),
+        };
+
+        AbsInfo(evdev::AbsInfo::new(0, min, max, a.fuzz(), a.flat(), 0))
+    }
+
+    /// Combine the normalised inputs so axes with different ranges carry the same weight
+    fn update(&mut self) {
+        let [a, b] = self.inputs.map(|i| i.normalised());
+        let value = match self.op {
+            CombineOp::Sum => (a + b).clamp(-1.0, 1.0),
+            CombineOp::Average => (a + b) / 2.0,
+            CombineOp::Difference => (a - b) / 2.0,
+            CombineOp::Max => a.max(b),
+        };
+        self.axis_info = self.axis_info.clone_set_normalised(value);
+    }
+
+    pub fn axis_type(&self) -> AbsoluteAxisType {
+        self.axis_type
+    }
+
+    pub fn axis_info(&self) -> AbsInfo {
+        self.axis_info
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32) {
+        self.inputs[input] = self.inputs[input].clone_set_value(value);
+        self.update();
+    }
+
+    pub fn to_evdev_event(&self) -> InputEvent {
+        InputEvent::new(
+            evdev::EventType::ABSOLUTE,
+            self.axis_type.0 .0,
+            self.axis_info.0.value(),
+        )
+    }
+}
+
+/// An output axis set by an expression over the normalised values of input axes
+#[derive(Clone, Debug)]
+pub struct ExpressionAxisOutputEvent {
+    axis_type: AbsoluteAxisType,
+    axis_info: AbsInfo,
+    inputs: Vec<(AbsInfo, ValueRange)>,
+    expression: Expression,
+    range: ValueRange,
+}
+
+impl ExpressionAxisOutputEvent {
+    /// Without an output range the output has the range of the first input
+    pub fn new(
+        axis_type: AbsoluteAxisType,
+        inputs: Vec<(AbsInfo, ValueRange)>,
+        expression: Expression,
+        range: ValueRange,
+        output_range: Option<OutputRange>,
+    ) -> Self {
+        let mut event = ExpressionAxisOutputEvent {
+            axis_type,
+            axis_info: output_range.map_or(inputs[0].0, |r| r.axis_info()),
+            inputs,
+            expression,
+            range,
+        };
+        event.update();
+        event
+    }
+
+    pub fn axis_type(&self) -> AbsoluteAxisType {
+        self.axis_type
+    }
+
+    pub fn axis_info(&self) -> AbsInfo {
+        self.axis_info
+    }
+
+    fn update(&mut self) {
+        let values: Vec<f64> = self
+            .inputs
+            .iter()
+            .map(|(info, range)| range.scale(info.normalised()))
+            .collect();
+
+        let value = self.range.normalise(self.expression.evaluate(&values));
+        // Dividing by zero shouldn't move the axis to an end
+        let value = match value.is_nan() {
+            true => 0.0,
+            false => value,
+        };
+        self.axis_info = self.axis_info.clone_set_normalised(value);
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32) {
+        self.inputs[input].0 = self.inputs[input].0.clone_set_value(value);
+        self.update();
+    }
+
+    pub fn to_evdev_event(&self) -> InputEvent {
+        InputEvent::new(
+            evdev::EventType::ABSOLUTE,
+            self.axis_type.0 .0,
+            self.axis_info.0.value(),
+        )
+    }
+}
+
+/// Keys pressed in order and released in reverse order on the virtual keyboard
+#[derive(Clone, Debug)]
+pub struct KeyComboOutputEvent {
+    keys: Vec<KeyCode>,
+    pressed: bool,
+    changed: bool,
+}
+
+impl KeyComboOutputEvent {
+    pub fn new(keys: Vec<KeyCode>) -> Self {
+        KeyComboOutputEvent {
+            keys,
+            pressed: false,
+            changed: false,
+        }
+    }
+
+    pub fn codes(&self) -> Vec<KeyCode> {
+        self.keys.clone()
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        let pressed = match value {
+            0 => false,
+            1 => true,
+            // Ignore auto repeat
+            _ => self.pressed,
+        };
+        self.changed = pressed != self.pressed;
+        self.pressed = pressed;
+    }
+
+    /// Each key in its own report so modifiers are seen before the key they modify
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        if !self.changed {
+            return vec![];
+        }
+
+        let keys: Vec<&KeyCode> = match self.pressed {
+            true => self.keys.iter().collect(),
+            false => self.keys.iter().rev().collect(),
+        };
+
+        let report = InputEvent::new(evdev::EventType::SYNCHRONIZATION, 0, 0);
+        let mut events: Vec<InputEvent> = keys
+            .into_iter()
+            .flat_map(|k| {
+                let key = InputEvent::new(evdev::EventType::KEY, k.0 .0, self.pressed as i32);
+                [key, report.clone()]
+            })
+            .collect();
+
+        // The device adds the last report
+        events.pop();
+        events
+    }
+}
+
+/// A named layer used by the conditions of other mappings, writes nothing itself
+#[derive(Clone, Debug)]
+pub struct LayerOutputEvent {
+    layer: String,
+    toggle: bool,
+    active: bool,
+}
+
+impl LayerOutputEvent {
+    pub fn new(config: LayerConfig) -> Self {
+        LayerOutputEvent {
+            layer: config.layer,
+            toggle: config.toggle,
+            active: false,
+        }
+    }
+
+    pub fn layer(&self) -> &str {
+        &self.layer
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn set_value(&mut self, value: i32) {
+        match (self.toggle, value) {
+            (true, 1) => self.active = !self.active,
+            (false, 0 | 1) => self.active = value == 1,
+            // Ignore auto repeat and releases of toggles
+            _ => (),
+        }
+    }
+}
+
+/// Offsets an axis written by other mappings, nudged up or down by buttons
+#[derive(Clone, Debug)]
+pub struct TrimOutputEvent {
+    config: TrimConfig,
+    /// Range and last untrimmed value of the trimmed axis
+    axis_info: AbsInfo,
+    /// Fraction of the full range added to the axis
+    offset: f64,
+    changed: bool,
+}
+
+impl TrimOutputEvent {
+    pub fn new(config: TrimConfig, axis_info: AbsInfo, offset: f64) -> Self {
+        let offset = offset.clamp(-config.limit, config.limit);
+        TrimOutputEvent {
+            config,
+            axis_info,
+            offset,
+            changed: false,
+        }
+    }
+
+    pub fn config(&self) -> &TrimConfig {
+        &self.config
+    }
+
+    pub fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    /// Whether the last button press moved the trim
+    pub fn changed(&self) -> bool {
+        self.changed
+    }
+
+    fn trimmed_event(&self) -> InputEvent {
+        let info = self.axis_info.0;
+        let range = (info.maximum() - info.minimum()) as f64;
+        let value = info.value() as f64 + self.offset * range;
+        let value = (value.round() as i32).clamp(info.minimum(), info.maximum());
+        InputEvent::new(evdev::EventType::ABSOLUTE, self.config.trim.0 .0, value)
+    }
+
+    /// Record an untrimmed value of the axis written by another mapping and return it trimmed
+    pub fn apply(&mut self, value: i32) -> InputEvent {
+        self.axis_info = self.axis_info.clone_set_value(value);
+        self.trimmed_event()
+    }
+
+    pub fn set_value(&mut self, input: usize, value: i32) {
+        // Only presses move the trim
+        self.changed = value == 1;
+        if !self.changed {
+            return;
+        }
+
+        let offset = match input {
+            0 => self.offset + self.config.step,
+            1 => self.offset - self.config.step,
+            _ => 0.0,
+        };
+        self.offset = offset.clamp(-self.config.limit, self.config.limit);
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        match self.changed {
+            true => vec![self.trimmed_event()],
+            false => vec![],
+        }
+    }
+}
+
+/// Events of another output after its axes were adjusted, e.g. by a trim
+#[derive(Clone, Debug)]
+pub struct AdjustedOutputEvent {
+    device: OutputDevice,
+    events: Vec<InputEvent>,
+}
+
+impl AdjustedOutputEvent {
+    pub fn new(device: OutputDevice, events: Vec<InputEvent>) -> Self {
+        AdjustedOutputEvent { device, events }
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.events.clone()
+    }
+}
+
 // Can't just use config directly as we need to clone the input axis info and values
 #[derive(Clone, Debug)]
 pub enum OutputEvent {
     AbsAxis(AbsAxisOutputEvent),
     Key(KeyOutputEvent),
+    RelAxis(RelAxisOutputEvent),
     Synchronization(SyncOutputEvent),
     FilteredAbsAxis(FilteredAbsAxisOutputEvent),
+    SectorKeys(SectorKeysOutputEvent),
+    StickAxis(StickAxisOutputEvent),
+    CombinedAxis(CombinedAxisOutputEvent),
+    ExpressionAxis(ExpressionAxisOutputEvent),
+    SplitAxis(SplitAxisOutputEvent),
+    StickMouse(StickMouseOutputEvent),
+    Encoder(EncoderOutputEvent),
+    RampAxis(RampAxisOutputEvent),
+    DetentAxis(DetentAxisOutputEvent),
+    KeyCombo(KeyComboOutputEvent),
+    Layer(LayerOutputEvent),
+    SmoothedAxis(SmoothedAxisOutputEvent),
+    Trim(TrimOutputEvent),
+    Script(ScriptOutputEvent),
+    /// Only created while processing events, never part of a mapping
+    Adjusted(AdjustedOutputEvent),
+}
+
+/// The virtual devices output events can be written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OutputDevice {
+    Gamepad,
+    Mouse,
+    Keyboard,
+}
+
+impl OutputDevice {
+    /// The kind of device a key usually comes from
+    pub fn for_key(key: KeyCode) -> Self {
+        use evdev::KeyCode as K;
+
+        let code = key.0 .0;
+        let keyboard = code < K::BTN_0.0
+            || (K::KEY_OK.0..K::BTN_DPAD_UP.0).contains(&code)
+            || (K::KEY_ALS_TOGGLE.0..K::BTN_TRIGGER_HAPPY1.0).contains(&code);
+
+        match code {
+            _ if keyboard => OutputDevice::Keyboard,
+            c if (K::BTN_LEFT.0..=K::BTN_TASK.0).contains(&c) => OutputDevice::Mouse,
+            _ => OutputDevice::Gamepad,
+        }
+    }
 }
 
 impl OutputEvent {
-    pub fn clone_set_value(&self, value: i32) -> Self {
+    /// Update the value of one of the mapping's inputs, `input` being its index in the mapping
+    pub fn set_value(&mut self, input: usize, value: i32, now: Instant) {
         match self {
-            OutputEvent::AbsAxis(a) => OutputEvent::AbsAxis(a.clone_set_value(value)),
-            OutputEvent::Key(k) => OutputEvent::Key(KeyOutputEvent::new(k.code(), value)),
-            OutputEvent::Synchronization(s) => {
-                OutputEvent::Synchronization(s.clone_set_value(value))
-            }
-            OutputEvent::FilteredAbsAxis(f) => {
-                OutputEvent::FilteredAbsAxis(f.clone_set_value(value))
+            OutputEvent::AbsAxis(a) => a.set_value(value),
+            OutputEvent::Key(k) => k.set_value(value),
+            OutputEvent::RelAxis(r) => r.set_value(value),
+            OutputEvent::Synchronization(s) => s.set_value(value),
+            OutputEvent::FilteredAbsAxis(f) => f.set_value(value, now),
+            OutputEvent::SectorKeys(s) => s.set_value(input, value),
+            OutputEvent::StickAxis(s) => s.set_value(input, value),
+            OutputEvent::CombinedAxis(c) => c.set_value(input, value),
+            OutputEvent::ExpressionAxis(e) => e.set_value(input, value),
+            OutputEvent::SplitAxis(s) => s.set_value(value),
+            OutputEvent::StickMouse(m) => m.set_value(input, value, now),
+            OutputEvent::Encoder(e) => e.set_value(value, now),
+            OutputEvent::RampAxis(r) => r.set_value(input, value, now),
+            OutputEvent::DetentAxis(d) => d.set_value(value),
+            OutputEvent::KeyCombo(k) => k.set_value(value),
+            OutputEvent::Layer(l) => l.set_value(value),
+            OutputEvent::SmoothedAxis(s) => s.set_value(value, now),
+            OutputEvent::Trim(t) => t.set_value(input, value),
+            // Scripts need the input state so are run by the mapping
+            OutputEvent::Script(_) | OutputEvent::Adjusted(_) => (),
+        }
+    }
+
+    /// When this output next needs to be updated even if no input arrives
+    pub fn next_deadline(&self) -> Option<Instant> {
+        match self {
+            OutputEvent::FilteredAbsAxis(f) => f.next_deadline(),
+            OutputEvent::StickMouse(m) => m.next_deadline(),
+            OutputEvent::Encoder(e) => e.next_deadline(),
+            OutputEvent::RampAxis(r) => r.next_deadline(),
+            OutputEvent::SmoothedAxis(s) => s.next_deadline(),
+            OutputEvent::Script(s) => s.next_deadline(),
+            _ => None,
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        match self {
+            OutputEvent::FilteredAbsAxis(f) => f.tick(now),
+            OutputEvent::StickMouse(m) => m.tick(now),
+            OutputEvent::Encoder(e) => e.tick(now),
+            OutputEvent::RampAxis(r) => r.tick(now),
+            OutputEvent::SmoothedAxis(s) => s.tick(now),
+            _ => (),
+        }
+    }
+
+    /// The output to write now, scripts hand over their events instead of being copied
+    pub fn snapshot(&mut self) -> OutputEvent {
+        let device = self.device();
+        match self {
+            OutputEvent::Script(s) => {
+                OutputEvent::Adjusted(AdjustedOutputEvent::new(device, s.take_events()))
             }
+            _ => self.clone(),
+        }
+    }
+
+    /// The virtual device this output is written to
+    pub fn device(&self) -> OutputDevice {
+        match self {
+            OutputEvent::Key(k) => k.device,
+            OutputEvent::StickMouse(_) | OutputEvent::RelAxis(_) => OutputDevice::Mouse,
+            OutputEvent::KeyCombo(_) => OutputDevice::Keyboard,
+            OutputEvent::Adjusted(a) => a.device,
+            _ => OutputDevice::Gamepad,
+        }
+    }
+
+    /// The absolute axes written by this output and their ranges
+    pub fn abs_axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        match self {
+            OutputEvent::AbsAxis(a) => vec![(a.axis_type, a.axis_info)],
+            OutputEvent::CombinedAxis(c) => vec![(c.axis_type(), c.axis_info())],
+            OutputEvent::ExpressionAxis(e) => vec![(e.axis_type(), e.axis_info())],
+            OutputEvent::RampAxis(r) => vec![(r.axis_type(), r.axis_info())],
+            OutputEvent::DetentAxis(d) => vec![(d.axis_type(), d.axis_info())],
+            OutputEvent::SmoothedAxis(s) => vec![(s.axis_type(), s.axis_info())],
+            OutputEvent::StickAxis(s) => s.axes(),
+            OutputEvent::SplitAxis(s) => s.axes(),
+            OutputEvent::Script(s) => s.axes(),
+            _ => vec![],
+        }
+    }
+
+    /// The relative axes written by this output
+    pub fn rel_axes(&self) -> Vec<RelativeAxisType> {
+        match self {
+            OutputEvent::StickMouse(_) => vec![
+                RelativeAxisType(evdev::RelativeAxisCode::REL_X),
+                RelativeAxisType(evdev::RelativeAxisCode::REL_Y),
+            ],
+            OutputEvent::RelAxis(r) => vec![r.axis_type()],
+            _ => vec![],
+        }
+    }
+
+    /// The keys written by this output
+    pub fn keys(&self) -> Vec<KeyCode> {
+        match self {
+            OutputEvent::Key(k) => vec![k.code()],
+            OutputEvent::FilteredAbsAxis(f) => f.codes(),
+            OutputEvent::SectorKeys(s) => s.codes(),
+            OutputEvent::Encoder(e) => e.codes(),
+            OutputEvent::DetentAxis(d) => d.codes(),
+            OutputEvent::KeyCombo(k) => k.codes(),
+            OutputEvent::Script(s) => s.codes(),
+            _ => vec![],
         }
     }
 
@@ -159,8 +1504,24 @@ impl OutputEvent {
         match self {
             OutputEvent::AbsAxis(a) => vec![a.to_evdev_event()],
             OutputEvent::Key(k) => vec![k.to_evdev_event()],
+            OutputEvent::RelAxis(r) => vec![r.to_evdev_event()],
             OutputEvent::Synchronization(s) => vec![s.to_evdev_event()],
             OutputEvent::FilteredAbsAxis(f) => f.to_evdev_events(),
+            OutputEvent::SectorKeys(s) => s.to_evdev_events(),
+            OutputEvent::StickAxis(s) => s.to_evdev_events(),
+            OutputEvent::CombinedAxis(c) => vec![c.to_evdev_event()],
+            OutputEvent::ExpressionAxis(e) => vec![e.to_evdev_event()],
+            OutputEvent::SplitAxis(s) => s.to_evdev_events(),
+            OutputEvent::StickMouse(m) => m.to_evdev_events(),
+            OutputEvent::Encoder(e) => e.to_evdev_events(),
+            OutputEvent::RampAxis(r) => r.to_evdev_events(),
+            OutputEvent::DetentAxis(d) => d.to_evdev_events(),
+            OutputEvent::KeyCombo(k) => k.to_evdev_events(),
+            OutputEvent::Layer(_) => vec![],
+            OutputEvent::SmoothedAxis(s) => s.to_evdev_events(),
+            OutputEvent::Trim(t) => t.to_evdev_events(),
+            OutputEvent::Script(s) => s.to_evdev_events(),
+            OutputEvent::Adjusted(a) => a.to_evdev_events(),
         }
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+
+use evdev::EventSummary;
+use log::info;
+
+use crate::{
+    config::{ControllerId, Profile, Profiles},
+    device::DeviceInfo,
+    error::FatalError,
+    ew_types::{InputEvent, KeyCode},
+};
+
+/// Tracks the active profile and the buttons held on each device so that
+/// configured button combinations can switch between profiles
+pub struct ProfileSwitcher {
+    profiles: Profiles,
+    active: String,
+    held: HashMap<ControllerId, HashSet<KeyCode>>,
+}
+
+impl ProfileSwitcher {
+    pub fn new(
+        profiles: Profiles,
+        active: Option<String>,
+        infos: &HashMap<ControllerId, DeviceInfo>,
+    ) -> Result<Self, FatalError> {
+        let active = match active {
+            Some(name) => name,
+            None => profiles
+                .keys()
+                .next()
+                .ok_or("Config does not contain any profiles")?
+                .clone(),
+        };
+
+        if !profiles.contains_key(&active) {
+            Err(format!("No profile named {:?} in config", active))?
+        }
+
+        for (name, profile) in profiles.iter() {
+            let Some(switch) = &profile.switch else {
+                continue;
+            };
+
+            let info = &infos[&switch.id];
+            if let Some(key) = switch.keys.iter().find(|k| !info.key_info.contains(k)) {
+                Err(format!(
+                    "Switch key {:?} for profile {:?} is not supported by device {:?}",
+                    key, name, switch.id
+                ))?
+            }
+        }
+
+        Ok(ProfileSwitcher {
+            profiles,
+            active,
+            held: HashMap::new(),
+        })
+    }
+
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    pub fn active_profile(&self) -> &Profile {
+        &self.profiles[&self.active]
+    }
+
+    pub fn profile(&self, name: &str) -> &Profile {
+        &self.profiles[name]
+    }
+
+    pub fn set_active(&mut self, name: String) {
+        info!("Switched to profile {:?}", name);
+        self.active = name;
+    }
+
+    /// The profile after the active one, wrapping around to the first
+    pub fn next_profile(&self) -> String {
+        let mut names = self.profiles.keys().cycle();
+        names.find(|n| **n == self.active);
+        names.next().unwrap_or(&self.active).clone()
+    }
+
+    /// Record the state of a button and return the profile to switch to if
+    /// this press completed the button combination of an inactive profile.
+    pub fn process(&mut self, id: &ControllerId, event: &InputEvent) -> Option<String> {
+        let EventSummary::Key(_, code, value) = event.kind() else {
+            return None;
+        };
+
+        let key = KeyCode(code);
+        let held = self.held.entry(id.clone()).or_default();
+        match value {
+            0 => {
+                held.remove(&key);
+                return None;
+            }
+            1 => {
+                held.insert(key);
+            }
+            // Ignore auto repeat
+            _ => return None,
+        }
+
+        self.profiles
+            .iter()
+            .filter(|(name, _)| **name != self.active)
+            .find(|(_, p)| match &p.switch {
+                Some(s) => s.id == *id && s.keys.contains(&key) && s.keys.is_subset(held),
+                None => false,
+            })
+            .map(|(name, _)| name.clone())
+    }
+}
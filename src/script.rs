@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use rhai::{
+    module_resolvers::DummyModuleResolver, CallFnOptions, Dynamic, Engine, EvalAltResult, Map,
+    Scope, AST,
+};
+
+use crate::{
+    config::{ControllerId, ControllerInputEvent, ScriptConfig},
+    error::{FatalError, NonFatalError},
+    ew_types::{AbsInfo, AbsoluteAxisType, InputEvent, KeyCode},
+    input_state::InputState,
+};
+
+/// Called with each change of an input
+const ON_INPUT: &str = "on_input";
+/// Called when a timer requested by the script is due
+const ON_TIMER: &str = "on_timer";
+
+/// Engine that can't reach the file system or run forever
+fn sandboxed_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(100_000)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(4096)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .on_print(|s| info!("script: {}", s))
+        .on_debug(|s, _, pos| debug!("script {}: {}", pos, s));
+    engine.disable_symbol("eval");
+    engine
+}
+
+fn script_error(name: &str, e: Box<EvalAltResult>) -> NonFatalError {
+    NonFatalError::from(format!("Script {} failed. {}", name, e))
+}
+
+fn device_name(id: &ControllerId) -> String {
+    match id {
+        ControllerId::Path(path) => path.display().to_string(),
+        ControllerId::Name(name) => name.clone(),
+    }
+}
+
+fn input_name(input: &ControllerInputEvent) -> String {
+    match input {
+        ControllerInputEvent::AbsAxis(a) => format!("{:?}", a.0),
+        ControllerInputEvent::RelAxis(r) => format!("{:?}", r.0),
+        ControllerInputEvent::Key(k) => format!("{:?}", k.0),
+        ControllerInputEvent::Synchronization(s) => format!("{:?}", s.0),
+    }
+}
+
+/// A user script that decides which buttons and axes to write
+#[derive(Clone, Debug)]
+pub struct ScriptOutputEvent {
+    config: ScriptConfig,
+    inputs: Vec<ControllerInputEvent>,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+    /// Kept between calls as `this` so scripts can remember things
+    memory: Dynamic,
+    axes: BTreeMap<u16, AbsInfo>,
+    timers: Vec<Instant>,
+    events: Vec<InputEvent>,
+}
+
+impl ScriptOutputEvent {
+    pub fn new(
+        config: ScriptConfig,
+        inputs: Vec<ControllerInputEvent>,
+    ) -> Result<Self, FatalError> {
+        let engine = sandboxed_engine();
+        let ast = engine
+            .compile_file(config.script.clone())
+            .map_err(|e| format!("Failed to load script {:?}. {}", config.script, e))?;
+
+        if !ast.iter_functions().any(|f| f.name == ON_INPUT) {
+            Err(format!(
+                "Script {:?} must define fn {}(event, state)",
+                config.script, ON_INPUT
+            ))?;
+        }
+
+        let axes = config
+            .axes
+            .iter()
+            .map(|a| {
+                let info = AbsInfo(evdev::AbsInfo::new(a.min, a.min, a.max, 0, 0, 0));
+                (a.axis.0 .0, info)
+            })
+            .collect();
+
+        Ok(ScriptOutputEvent {
+            config,
+            inputs,
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+            memory: Map::new().into(),
+            axes,
+            timers: Vec::new(),
+            events: Vec::new(),
+        })
+    }
+
+    pub fn axes(&self) -> Vec<(AbsoluteAxisType, AbsInfo)> {
+        self.config
+            .axes
+            .iter()
+            .map(|a| (a.axis, self.axes[&a.axis.0 .0]))
+            .collect()
+    }
+
+    pub fn codes(&self) -> Vec<KeyCode> {
+        self.config.keys.clone()
+    }
+
+    /// Values of the inputs of every device by device and input name and the active layers
+    fn state_map(state: &InputState) -> Map {
+        let mut inputs = Map::new();
+        for (input, value) in state.values() {
+            let device = inputs
+                .entry(device_name(&input.id).into())
+                .or_insert_with(|| Map::new().into());
+            if let Some(mut device) = device.write_lock::<Map>() {
+                device.insert(input_name(&input.event).into(), Dynamic::from(value as i64));
+            }
+        }
+        let layers: rhai::Array = state.layers().map(|l| Dynamic::from(l.clone())).collect();
+
+        Map::from([
+            ("inputs".into(), inputs.into()),
+            ("layers".into(), layers.into()),
+        ])
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Dynamic>, now: Instant) -> Result<(), NonFatalError> {
+        self.events.clear();
+
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.memory);
+        let result = self
+            .engine
+            .call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| script_error(name, e))?;
+
+        if result.is_unit() {
+            return Ok(());
+        }
+
+        let outputs = result
+            .try_cast::<rhai::Array>()
+            .ok_or_else(|| format!("Script {} must return an array of outputs", name))?;
+        for output in outputs {
+            self.add_output(output, now)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check an output returned by the script is one it declared and queue it
+    fn add_output(&mut self, output: Dynamic, now: Instant) -> Result<(), NonFatalError> {
+        let output = output
+            .try_cast::<Map>()
+            .ok_or("Script outputs must be maps")?;
+        let int = |field: &str| {
+            output
+                .get(field)
+                .and_then(|v| v.as_int().ok())
+                .ok_or_else(|| format!("Script output {:?} needs an integer {}", output, field))
+        };
+        let name = |field: &str| output.get(field).and_then(|v| v.clone().into_string().ok());
+
+        if output.contains_key("timer_ms") {
+            let ms = int("timer_ms")?.max(0) as u64;
+            self.timers.push(now + Duration::from_millis(ms));
+        } else if let Some(key) = name("key") {
+            let code = evdev::KeyCode::from_str(&key)
+                .ok()
+                .filter(|c| self.config.keys.iter().any(|k| k.0 == *c))
+                .ok_or_else(|| format!("Script can't write undeclared key {}", key))?;
+            let value = int("value")? as i32;
+            self.events
+                .push(InputEvent::new(evdev::EventType::KEY, code.0, value));
+        } else if let Some(axis) = name("axis") {
+            let code = evdev::AbsoluteAxisCode::from_str(&axis)
+                .ok()
+                .filter(|c| self.axes.contains_key(&c.0))
+                .ok_or_else(|| format!("Script can't write undeclared axis {}", axis))?;
+            let info = self.axes[&code.0];
+            let value = (int("value")? as i32).clamp(info.0.minimum(), info.0.maximum());
+            self.axes.insert(code.0, info.clone_set_value(value));
+            self.events
+                .push(InputEvent::new(evdev::EventType::ABSOLUTE, code.0, value));
+        } else {
+            Err(format!(
+                "Script output {:?} needs a key, axis or timer_ms",
+                output
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the script for a change of one of its inputs, `state` already has the new value
+    pub fn set_value(
+        &mut self,
+        input: usize,
+        value: i32,
+        state: &InputState,
+        now: Instant,
+    ) -> Result<(), NonFatalError> {
+        let event = Map::from([
+            ("input".into(), Dynamic::from(input as i64)),
+            ("code".into(), input_name(&self.inputs[input]).into()),
+            ("value".into(), Dynamic::from(value as i64)),
+        ]);
+        let state = Self::state_map(state);
+
+        self.call(ON_INPUT, vec![event.into(), state.into()], now)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().min().copied()
+    }
+
+    /// Run the script once for every timer that is due
+    pub fn tick(&mut self, now: Instant, state: &InputState) -> Result<(), NonFatalError> {
+        let due = self.timers.iter().filter(|t| **t <= now).count();
+        self.timers.retain(|t| *t > now);
+
+        let mut events = Vec::new();
+        for _ in 0..due {
+            let state = Self::state_map(state);
+            self.call(ON_TIMER, vec![state.into()], now)?;
+            events.append(&mut self.events);
+        }
+        self.events = events;
+
+        Ok(())
+    }
+
+    pub fn to_evdev_events(&self) -> Vec<InputEvent> {
+        self.events.clone()
+    }
+
+    /// Hand over the events of the last call without copying the script's memory
+    pub fn take_events(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
@@ -0,0 +1,28 @@
+use std::{collections::HashMap, fs::File, io::ErrorKind, path::Path};
+
+use crate::{
+    error::{FatalError, NonFatalError},
+    ew_types::AbsoluteAxisType,
+};
+
+/// Trim offsets kept between runs as fractions of each axis' full range
+pub type Trims = HashMap<AbsoluteAxisType, f64>;
+
+pub fn load(path: &Path) -> Result<Trims, FatalError> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_yaml::from_reader(file)?),
+        // Nothing has been trimmed yet
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Trims::new()),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Save the trim of one axis, keeping those of any other axes in the same file
+pub fn save(path: &Path, axis: AbsoluteAxisType, offset: f64) -> Result<(), NonFatalError> {
+    let mut trims = load(path).map_err(|e| e.to_string())?;
+    trims.insert(axis, offset);
+
+    let file = File::create(path)?;
+    serde_yaml::to_writer(file, &trims).map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -1,15 +1,98 @@
-use log::debug;
+use log::{debug, info};
 
-use crate::{ew_uinput::VirtualDevice, mapping::EventMapping};
-use std::io::Error;
+use crate::{
+    ew_uinput::VirtualDevice,
+    mapping::EventMapping,
+    output_event::{OutputDevice, OutputEvent},
+};
+use std::{collections::HashMap, io::Error};
 
-pub fn new_device(dev_mappings: &EventMapping) -> Result<VirtualDevice, Error> {
+/// The virtual devices written to by a set of mappings
+pub struct OutputDevices(HashMap<OutputDevice, VirtualDevice>);
+
+/// The devices needed by `dev_mappings`, the gamepad is always created
+fn needed_devices(dev_mappings: &EventMapping) -> Vec<OutputDevice> {
+    let mut kinds = vec![OutputDevice::Gamepad];
+    for event in dev_mappings.list_output_events() {
+        if !kinds.contains(&event.device()) {
+            kinds.push(event.device());
+        }
+    }
+
+    kinds
+}
+
+pub fn new_device(kind: OutputDevice, dev_mappings: &EventMapping) -> Result<VirtualDevice, Error> {
     let output_actions = dev_mappings.list_output_events();
-    let mut device = VirtualDevice::new(output_actions)?;
+    let mut device = VirtualDevice::new(kind, output_actions)?;
 
     for path in device.enumerate_dev_nodes_blocking()? {
-        debug!("Available as {}", path.display());
+        debug!("{:?} available as {}", kind, path.display());
     }
 
     Ok(device)
 }
+
+impl OutputDevices {
+    pub fn new(dev_mappings: &EventMapping) -> Result<Self, Error> {
+        let devices_or_error: Result<HashMap<_, _>, Error> = needed_devices(dev_mappings)
+            .into_iter()
+            .map(|kind| Ok((kind, new_device(kind, dev_mappings)?)))
+            .collect();
+
+        Ok(OutputDevices(devices_or_error?))
+    }
+
+    /// Rebuild only the devices whose axes or keys differ for `dev_mappings`
+    pub fn update(&mut self, dev_mappings: &EventMapping) -> Result<(), Error> {
+        let needed = needed_devices(dev_mappings);
+        self.0.retain(|kind, _| needed.contains(kind));
+
+        for kind in needed {
+            let unchanged = match self.0.get(&kind) {
+                Some(device) => device.has_capabilities(dev_mappings.list_output_events()),
+                None => false,
+            };
+
+            if !unchanged {
+                info!("Creating new virtual {:?}", kind);
+                self.0.insert(kind, new_device(kind, dev_mappings)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Leave every device with no keys pressed
+    pub fn release_keys(&mut self) -> Result<(), Error> {
+        for device in self.0.values_mut() {
+            device.release_keys()?;
+        }
+
+        Ok(())
+    }
+
+    /// Leave every device with no keys pressed and its axes centred
+    pub fn release_all(&mut self) -> Result<(), Error> {
+        for device in self.0.values_mut() {
+            device.release_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write events to the devices they belong to, one report per device
+    pub fn emit(&mut self, events: &[OutputEvent]) -> Result<(), Error> {
+        for (kind, device) in self.0.iter_mut() {
+            let device_events: Vec<OutputEvent> = events
+                .iter()
+                .filter(|e| e.device() == *kind)
+                .cloned()
+                .collect();
+
+            device.emit(&device_events)?;
+        }
+
+        Ok(())
+    }
+}